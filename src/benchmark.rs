@@ -21,20 +21,25 @@
 pub mod benchmark {
     use std::time::Instant;
     use log::info;
-    use sha2::Sha256;
-    use sha2::Digest;
+    use crate::hashing::hashing::HashAlgorithm;
 
     pub fn benchmark() {
+        for algorithm in HashAlgorithm::ALL {
+            benchmark_algorithm(algorithm);
+        }
+    }
+
+    fn benchmark_algorithm(algorithm: HashAlgorithm) {
         let size = 1024 * 1024 * 25;
         let times = 5;
 
-        info!("Performing SHA256 benchmark (size={}, times={})", size, times);
+        info!("Performing {} benchmark (size={}, times={})", algorithm, size, times);
         let mut durations = Vec::new();
         for _ in 0 .. times {
             let data = vec![0u8; size];
-            let mut hasher = Sha256::new();
+            let mut hasher = algorithm.hasher();
             let start_time = Instant::now();
-            hasher.update(data);
+            hasher.update(&data);
             hasher.finalize();
             let duration = Instant::now().duration_since(start_time);
             durations.push(duration.as_micros());
@@ -45,6 +50,6 @@ pub mod benchmark {
         // });
         let total: u128 = durations.iter().sum();
         let average_duration = total as f64 / times as f64;
-        info!("average: {} us", average_duration);
+        info!("average: {} us ({} MB/s)", average_duration, size as f64 / average_duration);
     }
-}
\ No newline at end of file
+}
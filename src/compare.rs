@@ -0,0 +1,132 @@
+// Copyright (c) 2022-2025 Chris Kroells
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+pub mod compare {
+    use std::path::Path;
+
+    use crate::db::db::{open_existing, Database, DatabaseError, DatabaseMetadata, Difference, MissingFiles, Which};
+
+    /// Result of comparing a root in one database with a root in another one.
+    #[derive(Debug)]
+    pub struct Comparison {
+        pub first: DatabaseMetadata,
+        pub second: DatabaseMetadata,
+        /// Number of files indexed for the first root.
+        pub first_count: u64,
+        /// Number of files indexed for the second root.
+        pub second_count: u64,
+        pub missing: MissingFiles,
+        /// Files with the same path in both roots, but differing contents.
+        pub differences: Vec<Difference>,
+    }
+
+    /// Compares a root in the first database with a root in the second one, by the paths of the
+    /// files relative to the roots.
+    ///
+    /// A root has to be named when a database holds several of them. Both databases are opened
+    /// read-only, such as a backup which should not be written to, so databases created by an
+    /// older version are read through compatibility views rather than upgraded.
+    pub fn compare(first: &Path, second: &Path, first_root: Option<&str>, second_root: Option<&str>) -> Result<Comparison, DatabaseError> {
+        // the second database is attached with the read-only flags of the connection.
+        let connection = open_existing(first, true)?;
+        let mut database = Database::new(&connection);
+        database.create_compatibility_view()?;
+        database.bind_second(second.to_str().unwrap())?;
+        database.select_root(Some(Which::First), first_root)?;
+        database.select_root(Some(Which::Second), second_root)?;
+
+        Ok(Comparison {
+            first: database.get_metadata(Some(Which::First))?,
+            second: database.get_metadata(Some(Which::Second))?,
+            first_count: database.get_count(Some(Which::First))?,
+            second_count: database.get_count(Some(Which::Second))?,
+            missing: database.find_missing()?,
+            differences: database.compare()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod compare_tests {
+    use std::fs;
+    use std::path::Path;
+    use rusqlite::Connection;
+    use crate::compare::compare::compare;
+    use crate::Database;
+    use crate::model::model::Entry;
+    use crate::testing::testing::TempDir;
+
+    fn write_database(path: &Path, root: &str, entries: &[(&str, &str)]) {
+        let connection = Connection::open(path).unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for(root, None, 1000, false, None).unwrap();
+        for (name, signature) in entries {
+            let abspath = format!("{}/{}", root, name);
+            database.add_entry(&Entry::new_simple(name, &abspath, name, root, signature, 100, 100, 100)).unwrap();
+        }
+    }
+
+    #[test]
+    fn missing_and_differing_files() {
+        let dir = TempDir::new("compare");
+        let (first, second) = (dir.join("first.db"), dir.join("second.db"));
+        write_database(&first, "/data", &[("same", "00aa"), ("changed", "00bb"), ("only_first", "00cc")]);
+        write_database(&second, "/backup", &[("same", "00aa"), ("changed", "00dd"), ("only_second", "00ee")]);
+
+        let comparison = compare(&first, &second, None, None).unwrap();
+        assert_eq!((3, 3), (comparison.first_count, comparison.second_count));
+        assert_eq!(vec!["only_second"], comparison.missing.missing_in_first);
+        assert_eq!(vec!["only_first"], comparison.missing.missing_in_second);
+        assert_eq!(1, comparison.differences.len());
+        let difference = &comparison.differences[0];
+        assert_eq!("changed", difference.path);
+        assert_eq!(("00bb", "00dd"), (difference.first_signature.as_str(), difference.second_signature.as_str()));
+        assert_eq!(None, difference.differing_ranges);
+    }
+
+    #[test]
+    fn databases_are_not_written() {
+        let dir = TempDir::new("compare-read-only");
+        let (first, old, missing) = (dir.join("first.db"), dir.join("old.db"), dir.join("missing.db"));
+        write_database(&first, "/data", &[("file", "00aa")]);
+        Connection::open(&old).unwrap().execute_batch(
+            "CREATE TABLE metadata (path TEXT PRIMARY KEY, last_updated INTEGER);
+            INSERT INTO metadata VALUES ('/backup', 1000);
+            CREATE TABLE entries (path TEXT PRIMARY KEY, abspath TEXT NOT NULL, basename TEXT NOT NULL,
+                dirname TEXT NOT NULL, signature TEXT NOT NULL, size INTEGER NOT NULL, timestamp INTEGER NOT NULL,
+                updated INTEGER NOT NULL);
+            INSERT INTO entries VALUES ('file', '/backup/file', 'file', '/backup', '00bb', 100, 100, 100);"
+        ).unwrap();
+        let old_contents = fs::read(&old).unwrap();
+
+        // created by an older version, either as the first or as the second database.
+        let [as_second, as_first] = [(&first, &old), (&old, &first)].map(|(first, second)| compare(first, second, None, None).unwrap());
+        let missing_result = compare(&first, &missing, None, None);
+
+        assert_eq!("backup", as_second.second.name);
+        for comparison in [as_second, as_first] {
+            assert_eq!((1, 1), (comparison.first_count, comparison.second_count));
+            assert_eq!(vec!["file"], comparison.differences.iter().map(|difference| difference.path.as_str()).collect::<Vec<_>>());
+        }
+        assert_eq!(old_contents, fs::read(&old).unwrap());
+        assert!(missing_result.is_err());
+        assert!(!missing.exists());
+    }
+}
@@ -21,48 +21,200 @@
 pub mod db {
     use std::fmt;
     use std::fmt::Formatter;
+    use std::ops::Range;
+    use std::path::Path;
     use log::{error, info};
-    use multimap::MultiMap;
-    use rusqlite::{Connection, Row, Result};
-    use model::Entry;
+    use rusqlite::{Connection, OpenFlags, Row};
+    use rusqlite::types::Type;
+    use model::{Block, Entry, MoveMethod, VerifyStatus};
+    use crate::hashing::hashing::{HashAlgorithm, ReadMode};
     use crate::model::model;
 
+    /// Results of database operations, failing with a `DatabaseError` by default.
+    pub type Result<T, E = DatabaseError> = std::result::Result<T, E>;
+
     pub struct Database<'a> {
         connection: &'a Connection,
+        /// Name of the root directory to read and write entries for. Queries over all entries
+        /// cover every root when none is selected.
+        root: Option<String>,
+        /// Name of the root directory selected in the attached second database.
+        second_root: Option<String>,
     }
 
     #[derive(Debug)]
     pub enum DatabaseError {
         EntryNotFound,
+        /// The hash algorithms of the databases being compared, or of a database and the requested
+        /// algorithm, differ.
+        AlgorithmMismatch(HashAlgorithm, HashAlgorithm),
+        /// No root directory with the given name is in the database.
+        RootNotFound(String),
+        /// The database holds several root directories, and none was selected.
+        AmbiguousRoot(Vec<String>),
+        /// A root directory with the given name is in the database, but for another path.
+        RootPathMismatch(String, String),
+        /// The operation reads or writes the entries of a root, but none was selected.
+        NoRootSelected,
+        Sqlite(rusqlite::Error),
         Unexpected,
     }
 
     impl fmt::Display for DatabaseError {
         fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-            // TODO implement proper error message.
-            write!(f, "{:?}", self)
+            match self {
+                DatabaseError::EntryNotFound => write!(f, "Entry not found."),
+                DatabaseError::AlgorithmMismatch(first, second) => write!(f, "Hash algorithms differ: {} and {}", first, second),
+                DatabaseError::RootNotFound(name) => write!(f, "No root named '{}' in the database.", name),
+                DatabaseError::AmbiguousRoot(names) => write!(f, "Database holds several roots, select one of: {}", names.join(", ")),
+                DatabaseError::RootPathMismatch(name, path) => write!(f, "Root '{}' is for '{}'", name, path),
+                DatabaseError::NoRootSelected => write!(f, "No root selected."),
+                DatabaseError::Sqlite(e) => write!(f, "Database error: {}", e),
+                DatabaseError::Unexpected => write!(f, "Unexpected database error."),
+            }
         }
     }
 
     impl std::error::Error for DatabaseError {}
 
+    impl From<rusqlite::Error> for DatabaseError {
+        fn from(error: rusqlite::Error) -> Self {
+            DatabaseError::Sqlite(error)
+        }
+    }
+
+    #[derive(Clone, Copy)]
     pub enum Which {
         First,
         Second
     }
 
+    /// A root directory indexed into the database.
     #[derive(Debug)]
     pub struct DatabaseMetadata {
+        /// Name identifying the root, which entries are keyed by along with their relative path.
+        pub name: String,
         pub path: String,
         pub last_updated: u64,
+        /// Algorithm used to compute the signatures of all entries.
+        pub algorithm: HashAlgorithm,
     }
 
     impl DatabaseMetadata {
-        pub fn new(path: String, last_updated: u64) -> DatabaseMetadata {
-            DatabaseMetadata { path, last_updated }
+        pub fn new(name: String, path: String, last_updated: u64, algorithm: HashAlgorithm) -> DatabaseMetadata {
+            DatabaseMetadata { name, path, last_updated, algorithm }
         }
     }
 
+    /// A run of a command against the database, such as indexing or scrubbing.
+    #[derive(Debug)]
+    pub struct Run {
+        pub command: String,
+        pub started: u64,
+        pub finished: Option<u64>,
+        /// Requested read mode for hashing files.
+        pub read_mode: String,
+        /// Number of files read with `fadvise` because `O_DIRECT` was not supported.
+        pub fallback_reads: Option<u64>,
+        /// Number of files which could not be processed.
+        pub errors: Option<u64>,
+    }
+
+    /// A file which failed to be read by one or more runs, with the most recent error.
+    #[derive(Debug)]
+    pub struct FailingFile {
+        /// Name of the root the file is in.
+        pub root: String,
+        /// Path relative to the root.
+        pub path: String,
+        /// Number of times reading the file failed.
+        pub count: u64,
+        pub first_failed: u64,
+        pub last_failed: u64,
+        /// Kind of the last error, such as `PermissionDenied`.
+        pub kind: String,
+        /// OS error number of the last error, such as `EIO`.
+        pub errno: Option<i32>,
+        /// Byte offset of the last failed read.
+        pub offset: Option<u64>,
+        pub message: String,
+    }
+
+    /// The last known state of a file which was removed from the index, as it no longer exists.
+    #[derive(Debug)]
+    pub struct DeletedEntry {
+        /// Name of the root the file was in.
+        pub root: String,
+        /// Path relative to the root.
+        pub path: String,
+        pub abspath: String,
+        /// Last known signature, to find a copy of the file by.
+        pub signature: String,
+        pub size: u64,
+        /// Last known modification time.
+        pub timestamp: i64,
+        pub link_target: Option<String>,
+        /// When the file was found to be deleted.
+        pub deleted: u64,
+    }
+
+    /// Files found in only one of the compared roots, by path relative to the root.
+    #[derive(Debug, Default)]
+    pub struct MissingFiles {
+        /// Files in the second root, but not in the first one.
+        pub missing_in_first: Vec<String>,
+        /// Files in the first root, but not in the second one.
+        pub missing_in_second: Vec<String>,
+    }
+
+    /// A file with the same path in both compared roots, but with differing contents.
+    #[derive(Debug)]
+    pub struct Difference {
+        /// Path relative to the roots.
+        pub path: String,
+        pub first_abspath: String,
+        pub first_signature: String,
+        pub first_timestamp: i64,
+        pub second_abspath: String,
+        pub second_signature: String,
+        pub second_timestamp: i64,
+        /// Byte ranges which differ, when both files were indexed with blocks of the same size.
+        pub differing_ranges: Option<Vec<Range<u64>>>,
+    }
+
+    /// Files with the same signature, which are not hard links to the same file.
+    #[derive(Debug)]
+    pub struct DupeGroup {
+        pub signature: String,
+        pub entries: Vec<Entry>,
+    }
+
+    /// Entries which are hard links to the same file.
+    #[derive(Debug)]
+    pub struct HardlinkGroup {
+        pub device: u64,
+        pub inode: u64,
+        pub entries: Vec<Entry>,
+    }
+
+    /// Columns of the tables read by queries, which databases created by older versions may lack.
+    const QUERIED_COLUMNS: [(&str, &[&str]); 6] = [
+        ("metadata", &["name", "path", "last_updated", "algorithm", "checkpoint", "block_size"]),
+        ("blocks", &["root", "path", "offset", "length", "signature"]),
+        ("runs", &["id", "command", "started", "finished", "read_mode", "fallback_reads", "errors"]),
+        ("errors", &["id", "run", "root", "path", "kind", "errno", "offset", "message", "time"]),
+        ("bad_ranges", &["root", "path", "start", "end", "first_seen", "last_seen"]),
+        ("entries", &[
+            "root", "path", "abspath", "basename", "dirname", "signature", "size", "timestamp", "updated",
+            "last_verified", "link_target", "device", "inode", "timestamp_nsec", "ctime", "ctime_nsec",
+            "verify_status", "added", "hashed"
+        ]),
+    ];
+
+    /// Columns selected for mapping rows to entries with `ROW_TO_ENTRY`.
+    const ENTRY_COLUMNS: &str = "path, abspath, basename, dirname, signature, size, timestamp, updated,
+                        link_target, device, inode, timestamp_nsec, ctime, ctime_nsec, hashed";
+
     const ROW_TO_ENTRY: fn(&Row) -> Result<Entry, rusqlite::Error> = |row: &Row| {
         Ok(Entry {
             path: row.get(0)?,
@@ -73,404 +225,1368 @@ pub mod db {
             size: row.get(5)?,
             timestamp: row.get(6)?,
             updated: row.get(7)?,
+            link_target: row.get(8)?,
+            device: row.get(9)?,
+            inode: row.get(10)?,
+            timestamp_nsec: row.get(11)?,
+            ctime: row.get(12)?,
+            ctime_nsec: row.get(13)?,
+            hashed: row.get(14)?,
         })
     };
 
+    /// Opens an existing database, failing instead of creating an empty one when there is no file
+    /// at the path.
+    pub fn open_existing(path: &Path, read_only: bool) -> Result<Connection> {
+        let mode = match read_only {
+            true => OpenFlags::SQLITE_OPEN_READ_ONLY,
+            false => OpenFlags::SQLITE_OPEN_READ_WRITE,
+        };
+        Ok(Connection::open_with_flags(path, mode | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX)?)
+    }
+
+    /// Collects the rows mapped by a query.
+    fn collect_rows<T>(rows: impl Iterator<Item = rusqlite::Result<T>>) -> Result<Vec<T>> {
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Logs a statement changing an unexpected number of rows, giving the error to return for it.
+    fn unexpected_changes(action: &str, updates: usize) -> DatabaseError {
+        error!("Unexpected number of changes {}: {}", action, updates);
+        DatabaseError::Sqlite(rusqlite::Error::StatementChangedRows(updates))
+    }
+
     impl Database<'_> {
-        pub fn new(connection: &Connection) -> Database {
-            Database { connection }
+        pub fn new(connection: &Connection) -> Database<'_> {
+            Database { connection, root: None, second_root: None }
         }
 
-        pub fn init_for(&self, path: &str, now_timestamp: u64, no_sync: bool) -> Result<(), rusqlite::Error> {
+        /// Prepares the database for indexing the given path, and selects its root.
+        ///
+        /// The root is looked up by name, or by path when no name is given. A path which was not
+        /// indexed before is added as a new root, named after its last component by default.
+        ///
+        /// All roots use the same algorithm, so that duplicates can be found across them. When no
+        /// algorithm is specified, a new database uses the default algorithm and an existing
+        /// database keeps using the algorithm it was created with.
+        pub fn init_for(&mut self, path: &str, name: Option<&str>, now_timestamp: u64, no_sync: bool, algorithm: Option<HashAlgorithm>) -> Result<(), DatabaseError> {
             if no_sync {
                 info!("Setting no sync to database.");
-                self.setup_pragma_disable_sync();
-            }
-            self.create_metadata_table();
-            if !self.has_metadata().unwrap() {
-                self.insert_metadata(path, now_timestamp);
-            } else {
-                let metadata = self.get_metadata(None).unwrap();
-                if !metadata.path.eq(path) {
-                    error!("Existing database is for '{}', not '{}'", metadata.path, path);
-                    panic!("Existing database is for '{}', not '{}'", metadata.path, path);
+                self.setup_pragma_disable_sync()?;
+            }
+            self.create_metadata_table()?;
+            self.upgrade_metadata_table()?;
+            let existing_algorithm = self.get_roots(None)?.first().map(|metadata| metadata.algorithm);
+            if let (Some(existing_algorithm), Some(algorithm)) = (existing_algorithm, algorithm) {
+                if algorithm != existing_algorithm {
+                    error!("Existing database uses '{}', not '{}'", existing_algorithm, algorithm);
+                    return Err(DatabaseError::AlgorithmMismatch(existing_algorithm, algorithm));
                 }
             }
+            match self.select_root_for(path, name) {
+                Ok(()) => {},
+                Err(DatabaseError::RootNotFound(name)) => {
+                    info!("Adding root '{}' for '{}'", name, path);
+                    self.insert_metadata(&name, path, now_timestamp, existing_algorithm.or(algorithm).unwrap_or_default())?;
+                    self.root = Some(name);
+                },
+                Err(DatabaseError::RootPathMismatch(name, existing_path)) => {
+                    error!("Existing root '{}' is for '{}', not '{}'. Give the root another name, or rebase it if it moved.", name, existing_path, path);
+                    return Err(DatabaseError::RootPathMismatch(name, existing_path));
+                },
+                Err(any) => return Err(any),
+            }
             info!("metadata path: {:?}", self.get_metadata(None)?);
 
-            self.create_entries_table();
-            self.create_entries_index();
+            self.create_entries_table()?;
+            self.create_entries_index()?;
+            self.upgrade_tables()?;
             Ok(())
         }
 
-        pub fn setup_pragma_disable_sync(&self) {
+        pub fn setup_pragma_disable_sync(&self) -> Result<()> {
             match self.connection.execute("PRAGMA main.synchronous = OFF", []) {
-                Ok(0) => {},
-                Ok(updates) => {
-                    error!("Unexpected number of changes when setting pragma: {}", updates);
-                    panic!("Unexpected number of changes when setting pragma: {}", updates);
-                },
+                Ok(0) => Ok(()),
+                Ok(updates) => Err(unexpected_changes("when setting pragma", updates)),
                 Err(why) => {
                     error!("Could not set pragma -> {}", why);
-                    panic!("Could not set pragma -> {}", why);
+                    Err(why.into())
                 }
             }
         }
 
-        pub fn bind_second(&self, path: &str) {
+        /// Starts a transaction grouping the following writes, so that they are synced to disk
+        /// once when committed, instead of once per statement.
+        ///
+        /// Methods running a transaction of their own, like `rebase_root`, cannot be called until
+        /// the batch is committed.
+        pub fn begin_batch(&self) -> Result<()> {
+            Ok(self.connection.execute_batch("BEGIN")?)
+        }
+
+        /// Commits the writes since `begin_batch`.
+        pub fn commit_batch(&self) -> Result<()> {
+            Ok(self.connection.execute_batch("COMMIT")?)
+        }
+
+        /// Attaches the database to compare with, read through temporary views named `second_entries`
+        /// and so on, which fill in the columns a database created by an older version lacks.
+        pub fn bind_second(&self, path: &str) -> Result<()> {
             match self.connection.execute("ATTACH ? AS second", [path]) {
-                Ok(0) => {},
-                Ok(updates) => {
-                    error!("Unexpected number of changes attaching database: {}", updates);
-                    panic!("Unexpected number of changes attaching database: {}", updates);
-                },
+                Ok(0) => self.create_views("second", "second_"),
+                Ok(updates) => Err(unexpected_changes("attaching database", updates)),
                 Err(why) => {
                     error!("Could not attach database: {} due to {}", path, why);
-                    panic!("Could not attach database: {} due to {}", path, why);
+                    Err(why.into())
                 }
             }
         }
 
-        fn create_metadata_table(&self) {
+        fn create_metadata_table(&self) -> Result<()> {
             match self.connection.execute(
                 "CREATE TABLE IF NOT EXISTS metadata (
                         path         TEXT PRIMARY KEY,
-                        last_updated INTEGER
+                        name         TEXT,
+                        last_updated INTEGER,
+                        algorithm    TEXT,
+                        checkpoint   TEXT,
+                        block_size   INTEGER
                     )",
                 (), // empty list of parameters.
             ) {
-                Ok(0) => {},
-                Ok(updates) => {
-                    error!("Unexpected number of changes during entries table creation: {}", updates);
-                    panic!("Unexpected number of changes during entries table creation: {}", updates);
-                },
+                Ok(_any) => Ok(()), // reports the changes of the previous statement.
                 Err(why) => {
                     error!("Unexpected error during entries table creation: {}", why);
-                    panic!("Unexpected error during entries table creation: {}", why);
+                    Err(why.into())
                 }
             }
         }
 
-        fn has_metadata(&self) -> Result<bool, DatabaseError> {
-            let x = self.connection.query_row("SELECT COUNT(1) FROM metadata", [], |row: &Row| -> rusqlite::Result<u64> {
-                Ok(row.get(0).unwrap())
-            });
-            match x {
-                Ok(1) => Ok(true),
-                Ok(0) => Ok(false),
-                Ok(_) => Err(DatabaseError::Unexpected),
-                Err(_) => Err(DatabaseError::Unexpected),
+        /// Adds columns introduced after the metadata table was first created.
+        ///
+        /// Roots created before a database could hold several of them are named after their path.
+        fn upgrade_metadata_table(&self) -> Result<()> {
+            self.add_column_if_missing("metadata", "algorithm", "TEXT")?;
+            self.add_column_if_missing("metadata", "checkpoint", "TEXT")?;
+            self.add_column_if_missing("metadata", "block_size", "INTEGER")?;
+            self.add_column_if_missing("metadata", "name", "TEXT")?;
+
+            let mut statement = self.connection.prepare("SELECT path FROM metadata WHERE name IS NULL")?;
+            let unnamed: Vec<String> = statement.query_map([], |row: &Row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            for path in unnamed {
+                info!("Naming root '{}' for '{}'", default_root_name(&path), path);
+                match self.connection.execute("UPDATE metadata SET name = ?1 WHERE path = ?2", [default_root_name(&path), path]) {
+                    Ok(1) => {},
+                    Ok(updates) => return Err(unexpected_changes("when naming root", updates)),
+                    Err(why) => {
+                        error!("Unexpected error during naming root -> {}", why);
+                        return Err(why.into());
+                    }
+                }
+            }
+
+            match self.connection.execute(
+                "CREATE UNIQUE INDEX IF NOT EXISTS idx_metadata_name ON metadata (name)",
+                (), // empty list of parameters.
+            ) {
+                Ok(_any) => Ok(()), // reports the changes of the previous statement.
+                Err(why) => {
+                    error!("Unexpected error during metadata index creation: {}", why);
+                    Err(why.into())
+                }
             }
         }
 
-        fn insert_metadata(&self, path: &str, now_timestamp: u64) {
+        fn insert_metadata(&self, name: &str, path: &str, now_timestamp: u64, algorithm: HashAlgorithm) -> Result<()> {
             match self.connection.execute(
-                "INSERT INTO metadata (path, last_updated) VALUES (?1, ?2)", [path, now_timestamp.to_string().as_str()]) {
-                Ok(1) => {},
-                Ok(updates) => {
-                    panic!("Unexpected number of changes when inserting into metadata table: {}", updates)
-                },
+                "INSERT INTO metadata (name, path, last_updated, algorithm) VALUES (?1, ?2, ?3, ?4)",
+                [name, path, now_timestamp.to_string().as_str(), algorithm.name()]) {
+                Ok(1) => Ok(()),
+                Ok(updates) => Err(unexpected_changes("when inserting into metadata table", updates)),
                 Err(why) => {
-                    panic!("Unexpected error during inserting into metadata table -> {}", why)
+                    error!("Unexpected error during inserting into metadata table -> {}", why);
+                    Err(why.into())
                 }
             }
         }
 
+        /// Gets the root directories in the database, ordered by name.
+        pub fn get_roots(&self, which: Option<Which>) -> Result<Vec<DatabaseMetadata>> {
+            let schema = match which {
+                None => "main",
+                Some(Which::First) => "main",
+                Some(Which::Second) => "second"
+            };
+            // databases created before the algorithm was recorded always used SHA-256.
+            let algorithm_column = match self.has_column(schema, "metadata", "algorithm")? {
+                true => "COALESCE(algorithm, 'sha256')",
+                false => "'sha256'",
+            };
+            // the main schema is left out, so that a compatibility view can take its place.
+            let table = match which {
+                Some(Which::Second) => "second_metadata",
+                _ => "metadata",
+            };
+            let mut statement = self.connection.prepare(
+                format!("SELECT name, path, last_updated, {} FROM {} ORDER BY name", algorithm_column, table).as_str()
+            )?;
+
+            let result_iter = statement.query_map([], |row: &Row| {
+                let algorithm: String = row.get(3)?;
+                Ok(DatabaseMetadata::new(
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    algorithm.parse().map_err(|why: String| rusqlite::Error::FromSqlConversionFailure(3, Type::Text, why.into()))?,
+                ))
+            })?;
+            collect_rows(result_iter)
+        }
+
+        /// Gets the selected root directory, or the first one by name when none is selected.
         pub fn get_metadata(&self, which: Option<Which>) -> Result<DatabaseMetadata> {
-            let table_name = match which {
-                None => "main.metadata",
-                Some(Which::First) => "main.metadata",
-                Some(Which::Second) => "second.metadata"
+            let selected = self.selected_root(which).map(|name| name.to_string());
+            self.get_roots(which)?
+                .into_iter()
+                .find(|metadata| selected.as_ref().is_none_or(|name| *name == metadata.name))
+                .ok_or(DatabaseError::Sqlite(rusqlite::Error::QueryReturnedNoRows))
+        }
+
+        /// Selects the root directory to read and write entries for.
+        ///
+        /// Without a name, the only root in the database is selected, failing when it holds several.
+        pub fn select_root(&mut self, which: Option<Which>, name: Option<&str>) -> Result<(), DatabaseError> {
+            let names: Vec<String> = self.get_roots(which)?.into_iter().map(|metadata| metadata.name).collect();
+            let selected = match name {
+                Some(name) if names.iter().any(|any| any == name) => name.to_string(),
+                Some(name) => return Err(DatabaseError::RootNotFound(name.to_string())),
+                None if names.len() == 1 => names[0].clone(),
+                None => return Err(DatabaseError::AmbiguousRoot(names)),
+            };
+            match which {
+                Some(Which::Second) => self.second_root = Some(selected),
+                _ => self.root = Some(selected),
+            }
+            Ok(())
+        }
+
+        /// Selects the root directory with the given name, or the one for the path when no name is given.
+        ///
+        /// When there is no such root, fails with the name a new root for the path would have.
+        pub fn select_root_for(&mut self, path: &str, name: Option<&str>) -> Result<(), DatabaseError> {
+            let roots = self.get_roots(None)?;
+            let name = match name {
+                Some(name) => name.to_string(),
+                None => roots.iter()
+                    .find(|metadata| metadata.path == path)
+                    .map_or_else(|| default_root_name(path), |metadata| metadata.name.clone()),
             };
-            let sql = format!("SELECT path, last_updated FROM {}", table_name);
-            Ok(self.connection.query_row(
-                &sql, [], |row: &Row| {
-                    Ok(DatabaseMetadata::new(
-                        row.get(0)?,
-                        row.get(1)?,
-                    ))
-                })?)
+            match roots.iter().find(|metadata| metadata.name == name) {
+                Some(metadata) if metadata.path != path => Err(DatabaseError::RootPathMismatch(name, metadata.path.clone())),
+                Some(_) => {
+                    self.root = Some(name);
+                    Ok(())
+                },
+                None => match roots.iter().find(|metadata| metadata.path == path) {
+                    // each path can only be indexed as one root.
+                    Some(metadata) => Err(DatabaseError::RootPathMismatch(metadata.name.clone(), path.to_string())),
+                    None => Err(DatabaseError::RootNotFound(name)),
+                },
+            }
+        }
+
+        fn selected_root(&self, which: Option<Which>) -> Option<&str> {
+            match which {
+                Some(Which::Second) => self.second_root.as_deref(),
+                _ => self.root.as_deref(),
+            }
+        }
+
+        /// Gets the name of the root which entries are read and written for.
+        fn root(&self) -> Result<&str> {
+            self.root_of(None)
+        }
+
+        fn root_of(&self, which: Option<Which>) -> Result<&str> {
+            self.selected_root(which).ok_or(DatabaseError::NoRootSelected)
+        }
+
+        /// Gets the path relative to the root where the last unfinished indexing run stopped.
+        pub fn get_checkpoint(&self) -> Result<Option<String>> {
+            Ok(self.connection.query_row("SELECT checkpoint FROM metadata WHERE name = ?1", [self.root()?], |row: &Row| {
+                row.get(0)
+            })?)
+        }
+
+        /// Sets the path where the indexing run stopped, or clears it once a full pass finished.
+        pub fn set_checkpoint(&self, checkpoint: Option<&str>) -> Result<()> {
+            match self.connection.execute("UPDATE metadata SET checkpoint = ?1 WHERE name = ?2", (checkpoint, self.root()?))? {
+                1 => Ok(()),
+                updates => Err(unexpected_changes("when setting checkpoint", updates)),
+            }
+        }
+
+        /// Gets the size of blocks to hash files in, if block signatures are stored.
+        pub fn get_block_size(&self) -> Result<Option<u64>> {
+            Ok(self.connection.query_row("SELECT block_size FROM metadata WHERE name = ?1", [self.root()?], |row: &Row| {
+                row.get(0)
+            })?)
+        }
+
+        pub fn set_block_size(&self, block_size: Option<u64>) -> Result<()> {
+            match self.connection.execute("UPDATE metadata SET block_size = ?1 WHERE name = ?2", (block_size, self.root()?))? {
+                1 => Ok(()),
+                updates => Err(unexpected_changes("when setting block size", updates)),
+            }
+        }
+
+        /// Moves the selected root to a new path, rewriting the absolute paths of its entries and of
+        /// the records of its deleted files in a single transaction. Returns the number of entries
+        /// rewritten.
+        pub fn rebase_root(&self, new_path: &str) -> Result<u64> {
+            let old_path = self.get_metadata(None)?.path;
+            // the paths of files are joined to the root without a trailing slash, however it was given.
+            let (old_prefix, new_prefix) = (old_path.trim_end_matches('/'), new_path.trim_end_matches('/'));
+            let transaction = self.connection.unchecked_transaction()?;
+            match transaction.execute("UPDATE metadata SET path = ?2 WHERE name = ?1", [self.root()?, new_path])? {
+                1 => {},
+                updates => return Err(unexpected_changes("when rebasing root", updates)),
+            }
+            // entries directly in the root have the root as dirname.
+            let updates = transaction.execute(
+                "UPDATE entries
+                    SET
+                        abspath = ?3 || substr(abspath, length(?2) + 1),
+                        dirname = ?3 || substr(dirname, length(?2) + 1)
+                    WHERE root = ?1 AND substr(abspath, 1, length(?2)) = ?2",
+                [self.root()?, old_prefix, new_prefix],
+            )?;
+            transaction.execute(
+                "UPDATE deleted_entries
+                    SET abspath = ?3 || substr(abspath, length(?2) + 1)
+                    WHERE root = ?1 AND substr(abspath, 1, length(?2)) = ?2",
+                [self.root()?, old_prefix, new_prefix],
+            )?;
+            transaction.commit()?;
+            Ok(updates as u64)
+        }
+
+        /// Selects a random sample of the files indexed for the selected root, leaving out links.
+        pub fn select_sample(&self, count: u64) -> Result<Vec<Entry>> {
+            let mut statement = self.connection.prepare(
+                format!("SELECT
+                        {}
+                    FROM entries
+                    WHERE root = ?1 AND link_target IS NULL
+                    ORDER BY RANDOM()
+                    LIMIT ?2", ENTRY_COLUMNS).as_str()
+            )?;
+            let entry_iter = statement.query_map((self.root()?, count), ROW_TO_ENTRY)?;
+
+            collect_rows(entry_iter)
         }
 
-        fn create_entries_table(&self) {
+        fn create_entries_table(&self) -> Result<()> {
             match self.connection.execute(
                 "CREATE TABLE IF NOT EXISTS entries (
-                        path      TEXT PRIMARY KEY,
+                        root      TEXT NOT NULL,
+                        path      TEXT NOT NULL,
                         abspath   TEXT NOT NULL,
                         basename  TEXT NOT NULL,
                         dirname   TEXT NOT NULL,
                         signature TEXT NOT NULL,
                         size      INTEGER NOT NULL,
                         timestamp INTEGER NOT NULL,
-                        updated   INTEGER NOT NULL
+                        updated   INTEGER NOT NULL,
+                        last_verified INTEGER,
+                        link_target TEXT,
+                        device    INTEGER,
+                        inode     INTEGER,
+                        timestamp_nsec INTEGER,
+                        ctime     INTEGER,
+                        ctime_nsec INTEGER,
+                        verify_status TEXT,
+                        added     INTEGER,
+                        hashed    INTEGER,
+                        PRIMARY KEY (root, path)
                     )",
                 (), // empty list of parameters.
             ) {
-                Ok(0) => {},
-                Ok(1) => {}, // can be either 0 or 1 for some reason...?
-                Ok(updates) => {
-                    panic!("Unexpected number of changes during entries table creation: {}", updates)
-                },
+                Ok(_any) => Ok(()), // reports the changes of the previous statement.
                 Err(why) => {
-                    panic!("Unexpected error during entries table creation: {}", why)
+                    error!("Unexpected error during entries table creation: {}", why);
+                    Err(why.into())
                 }
             }
         }
 
-        fn create_entries_index(&self) {
+        fn create_entries_index(&self) -> Result<()> {
             match self.connection.execute(
                 "CREATE INDEX IF NOT EXISTS idx_entries_signature ON entries (signature)",
                 (), // empty list of parameters.
             ) {
-                Ok(0) => {},
-                Ok(1) => {}, // can be either 0 or 1 for some reason...?
-                Ok(updates) => {
-                    panic!("Unexpected number of changes during entries index creation: {}", updates)
+                Ok(_any) => Ok(()), // reports the changes of the previous statement.
+                Err(why) => {
+                    error!("Unexpected error during entries index creation: {}", why);
+                    Err(why.into())
+                }
+            }
+        }
+
+        /// Creates or updates tables introduced after the database was first created.
+        pub fn upgrade_tables(&self) -> Result<()> {
+            self.upgrade_metadata_table()?;
+            self.upgrade_entries_table()?;
+            self.create_runs_table()?;
+            self.create_blocks_table()?;
+            self.create_errors_table()?;
+            self.create_bad_ranges_table()?;
+            self.add_root_to_tables()?;
+            self.create_deleted_entries_table()?;
+            self.create_moves_table()
+        }
+
+        /// Keys the rows of tables created before a database could hold several roots by root as
+        /// well, assigning them to the only root there was.
+        ///
+        /// The primary key cannot be altered, so the tables are created again and the rows copied.
+        fn add_root_to_tables(&self) -> Result<()> {
+            let outdated: Vec<&str> = ["entries", "blocks", "errors", "bad_ranges"].into_iter()
+                .filter(|table| self.has_column("main", table, "root").is_ok_and(|has_root| !has_root))
+                .collect();
+            if outdated.is_empty() {
+                return Ok(());
+            }
+
+            let root = self.get_metadata(None)?.name;
+            let transaction = self.connection.unchecked_transaction()?;
+            for table in outdated {
+                info!("Adding root '{}' to {} table.", root, table);
+                let columns = self.get_columns("main", table)?.join(", ");
+                transaction.execute(format!("ALTER TABLE {} RENAME TO outdated_{}", table, table).as_str(), ())?;
+                match table {
+                    "entries" => self.create_entries_table()?,
+                    "blocks" => self.create_blocks_table()?,
+                    "errors" => self.create_errors_table()?,
+                    _ => self.create_bad_ranges_table()?,
+                }
+                transaction.execute(
+                    format!("INSERT INTO {} (root, {}) SELECT ?1, {} FROM outdated_{}", table, columns, columns, table).as_str(),
+                    [&root],
+                )?;
+                transaction.execute(format!("DROP TABLE outdated_{}", table).as_str(), ())?;
+            }
+            // indexes were dropped along with the outdated entries table.
+            self.create_entries_index()?;
+            self.create_entries_inode_index()?;
+            Ok(transaction.commit()?)
+        }
+
+        /// Adds columns introduced after the entries table was first created.
+        fn upgrade_entries_table(&self) -> Result<()> {
+            self.add_column_if_missing("entries", "last_verified", "INTEGER")?;
+            self.add_column_if_missing("entries", "link_target", "TEXT")?;
+            self.add_column_if_missing("entries", "device", "INTEGER")?;
+            self.add_column_if_missing("entries", "inode", "INTEGER")?;
+            self.add_column_if_missing("entries", "timestamp_nsec", "INTEGER")?;
+            self.add_column_if_missing("entries", "ctime", "INTEGER")?;
+            self.add_column_if_missing("entries", "ctime_nsec", "INTEGER")?;
+            self.add_column_if_missing("entries", "verify_status", "TEXT")?;
+            self.add_column_if_missing("entries", "added", "INTEGER")?;
+            self.add_column_if_missing("entries", "hashed", "INTEGER")?;
+            self.create_entries_inode_index()
+        }
+
+        /// Lets a read-only connection query a database created by an older version.
+        ///
+        /// Tables missing columns are shadowed by temporary views filling them in with NULL, and
+        /// missing tables by empty temporary tables, leaving the database itself untouched. Rows
+        /// of a database created before it could hold several roots belong to the only root.
+        pub fn create_compatibility_view(&self) -> Result<()> {
+            self.create_views("main", "")
+        }
+
+        /// Creates temporary views over the queried tables of the schema, named after the tables
+        /// with the prefix, filling in the columns the tables lack.
+        ///
+        /// The main schema only gets views for outdated tables, which they shadow. An attached
+        /// schema gets views for all of them, as queries name the views rather than the tables.
+        fn create_views(&self, schema: &str, prefix: &str) -> Result<()> {
+            let root_name = match self.has_column(schema, "metadata", "name")? {
+                true => self.connection.query_row(format!("SELECT name FROM {}.metadata", schema).as_str(), [], |row: &Row| row.get(0))?,
+                false => default_root_name(&self.connection.query_row(format!("SELECT path FROM {}.metadata", schema).as_str(), [], |row: &Row| row.get::<_, String>(0))?),
+            };
+            if !self.has_column(schema, "blocks", "path")? {
+                self.connection.execute(
+                    format!("CREATE TEMP TABLE {}blocks (root TEXT, path TEXT, offset INTEGER, length INTEGER, signature TEXT)", prefix).as_str(),
+                    (),
+                )?;
+            }
+            for (table, columns) in QUERIED_COLUMNS {
+                let existing_columns = self.get_columns(schema, table)?;
+                let is_outdated = !columns.iter().all(|column| existing_columns.contains(&column.to_string()));
+                if existing_columns.is_empty() || (schema == "main" && !is_outdated) {
+                    continue;
+                }
+                let select_list: Vec<String> = columns.iter().map(|column| {
+                    match (existing_columns.contains(&column.to_string()), *column) {
+                        (true, _) => column.to_string(),
+                        (false, "name" | "root") => format!("'{}' AS {}", root_name.replace('\'', "''"), column),
+                        (false, _) => format!("NULL AS {}", column),
+                    }
+                }).collect();
+                self.connection.execute(
+                    format!("CREATE TEMP VIEW {}{} AS SELECT {} FROM {}.{}", prefix, table, select_list.join(", "), schema, table).as_str(),
+                    (),
+                )?;
+            }
+            Ok(())
+        }
+
+        fn get_columns(&self, schema: &str, table: &str) -> Result<Vec<String>> {
+            let mut statement = self.connection.prepare(
+                format!("PRAGMA {}.table_info({})", schema, table).as_str()
+            )?;
+            let columns = statement.query_map([], |row: &Row| -> rusqlite::Result<String> {
+                row.get(1)
+            })?;
+            collect_rows(columns)
+        }
+
+        fn has_column(&self, schema: &str, table: &str, column: &str) -> Result<bool> {
+            Ok(self.get_columns(schema, table)?.iter().any(|name| name == column))
+        }
+
+        fn add_column_if_missing(&self, table: &str, column: &str, definition: &str) -> Result<()> {
+            if self.has_column("main", table, column)? {
+                return Ok(());
+            }
+
+            match self.connection.execute(
+                format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition).as_str(),
+                (), // empty list of parameters.
+            ) {
+                Ok(_any) => {
+                    info!("Added column {} to {} table.", column, table);
+                    Ok(())
                 },
                 Err(why) => {
-                    panic!("Unexpected error during entries index creation: {}", why)
+                    error!("Unexpected error during adding column {} to {} table: {}", column, table, why);
+                    Err(why.into())
                 }
             }
         }
 
-        pub fn add_entry(&self, entry: &Entry) {
+        fn create_entries_inode_index(&self) -> Result<()> {
             match self.connection.execute(
-                "INSERT INTO entries
-                        (path, abspath, basename, dirname, signature, size, timestamp, updated)
-                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
-                    ON CONFLICT(path) DO UPDATE SET
-                        abspath = ?2,
-                        basename = ?3,
-                        dirname = ?4,
-                        signature = ?5,
-                        size = ?6,
-                        timestamp = ?7,
-                        updated = ?8",
-                (
-                    &entry.path, &entry.abspath, &entry.basename, &entry.dirname,
-                    &entry.signature, &entry.size, &entry.timestamp, &entry.updated),
+                "CREATE INDEX IF NOT EXISTS idx_entries_inode ON entries (device, inode)",
+                (), // empty list of parameters.
             ) {
-                Ok(_any) => {},
+                Ok(_any) => Ok(()), // reports the changes of the previous statement.
                 Err(why) => {
-                    panic!("Failed to add entry to table -> {}", why)
+                    error!("Unexpected error during entries index creation: {}", why);
+                    Err(why.into())
                 }
             }
         }
 
-        pub fn get_entry(&self, key: &String) -> Result<Entry, DatabaseError> {
-            let mut statement = self.connection.prepare(
-                "SELECT
-                        path,
-                        abspath,
-                        basename,
-                        dirname,
-                        signature,
-                        size,
-                        timestamp,
-                        updated
-                    FROM entries
-                    WHERE path = ?"
-            ).unwrap();
+        fn create_runs_table(&self) -> Result<()> {
+            match self.connection.execute(
+                "CREATE TABLE IF NOT EXISTS runs (
+                        id             INTEGER PRIMARY KEY,
+                        command        TEXT NOT NULL,
+                        started        INTEGER NOT NULL,
+                        finished       INTEGER,
+                        read_mode      TEXT NOT NULL,
+                        fallback_reads INTEGER,
+                        errors         INTEGER
+                    )",
+                (), // empty list of parameters.
+            ) {
+                Ok(_any) => {}, // reports the changes of the previous statement.
+                Err(why) => {
+                    error!("Unexpected error during runs table creation: {}", why);
+                    return Err(why.into());
+                }
+            }
+            self.add_column_if_missing("runs", "errors", "INTEGER")
+        }
 
-            let found_entry = statement.query_row([key], ROW_TO_ENTRY);
-            match found_entry {
-                Ok(entry) => Ok(entry),
-                Err(rusqlite::Error::QueryReturnedNoRows) => Err(DatabaseError::EntryNotFound),
-                Err(_any) => Err(DatabaseError::Unexpected)
+        fn create_errors_table(&self) -> Result<()> {
+            match self.connection.execute(
+                "CREATE TABLE IF NOT EXISTS errors (
+                        id      INTEGER PRIMARY KEY,
+                        run     INTEGER NOT NULL,
+                        root    TEXT NOT NULL,
+                        path    TEXT NOT NULL,
+                        kind    TEXT NOT NULL,
+                        errno   INTEGER,
+                        offset  INTEGER,
+                        message TEXT NOT NULL,
+                        time    INTEGER NOT NULL
+                    )",
+                (), // empty list of parameters.
+            ) {
+                Ok(_any) => Ok(()), // reports the changes of the previous statement.
+                Err(why) => {
+                    error!("Unexpected error during errors table creation: {}", why);
+                    Err(why.into())
+                }
             }
         }
 
-        pub fn remove_entry(&self, key: &String) -> Result<(), DatabaseError> {
-            let mut statement = self.connection.prepare(
-                "DELETE
-                    FROM entries
-                    WHERE path = ?"
-            ).unwrap();
+        fn create_bad_ranges_table(&self) -> Result<()> {
+            match self.connection.execute(
+                "CREATE TABLE IF NOT EXISTS bad_ranges (
+                        root       TEXT NOT NULL,
+                        path       TEXT NOT NULL,
+                        start      INTEGER NOT NULL,
+                        end        INTEGER NOT NULL,
+                        first_seen INTEGER NOT NULL,
+                        last_seen  INTEGER NOT NULL,
+                        PRIMARY KEY (root, path, start)
+                    )",
+                (), // empty list of parameters.
+            ) {
+                Ok(_any) => Ok(()), // reports the changes of the previous statement.
+                Err(why) => {
+                    error!("Unexpected error during bad ranges table creation: {}", why);
+                    Err(why.into())
+                }
+            }
+        }
 
-            match statement.execute([key]) {
-                Ok(1) => Ok(()),
-                Ok(updates) => panic!("Unexpected number of changes during entry removal: {}", updates),
-                Err(why) => panic!("Unexpected error during entry removal: {}", why)
+        fn create_deleted_entries_table(&self) -> Result<()> {
+            match self.connection.execute(
+                "CREATE TABLE IF NOT EXISTS deleted_entries (
+                        id          INTEGER PRIMARY KEY,
+                        root        TEXT NOT NULL,
+                        path        TEXT NOT NULL,
+                        abspath     TEXT NOT NULL,
+                        signature   TEXT NOT NULL,
+                        size        INTEGER NOT NULL,
+                        timestamp   INTEGER NOT NULL,
+                        link_target TEXT,
+                        deleted     INTEGER NOT NULL
+                    )",
+                (), // empty list of parameters.
+            ) {
+                Ok(_any) => Ok(()), // reports the changes of the previous statement.
+                Err(why) => {
+                    error!("Unexpected error during deleted entries table creation: {}", why);
+                    Err(why.into())
+                }
             }
         }
 
-        pub fn get_count(&self, which: Option<Which>) -> Result<u64> {
-            let table_name = match which {
-                None => "main.entries",
-                Some(Which::First) => "main.entries",
-                Some(Which::Second) => "second.entries"
-            };
+        /// Gets the files removed from the index, most recently deleted first.
+        ///
+        /// Covers all roots, unless one is selected.
+        pub fn get_deleted_entries(&self) -> Result<Vec<DeletedEntry>> {
+            if !self.has_column("main", "deleted_entries", "path")? {
+                return Ok(Vec::new()); // created by an older version.
+            }
             let mut statement = self.connection.prepare(
-                format!("SELECT COUNT(1) FROM {}", table_name).as_str()
+                "SELECT root, path, abspath, signature, size, timestamp, link_target, deleted
+                    FROM deleted_entries
+                    WHERE ?1 IS NULL OR root = ?1
+                    ORDER BY deleted DESC, id DESC"
             )?;
-            let count = statement.query_row([], |row: &Row| -> rusqlite::Result<u64> {
-                row.get(0)
-            }).unwrap();
 
-            Ok(count)
+            let result_iter = statement.query_map([&self.root], |row: &Row| {
+                Ok(DeletedEntry {
+                    root: row.get(0)?,
+                    path: row.get(1)?,
+                    abspath: row.get(2)?,
+                    signature: row.get(3)?,
+                    size: row.get(4)?,
+                    timestamp: row.get(5)?,
+                    link_target: row.get(6)?,
+                    deleted: row.get(7)?,
+                })
+            })?;
+            collect_rows(result_iter)
         }
 
-        pub fn get_size(&self) -> Result<u64> {
+        /// Removes the records of files of the selected root deleted at or before the given time,
+        /// so that a time of now prunes all of them. Returns the number of records removed.
+        pub fn prune_deleted_entries(&self, until_timestamp: u64) -> Result<u64> {
+            let updates = self.connection.execute(
+                "DELETE FROM deleted_entries WHERE root = ?1 AND deleted <= ?2",
+                (self.root()?, until_timestamp),
+            )?;
+            Ok(updates as u64)
+        }
+
+        /// Finds indexed files with the given signature in any root, such as to restore a deleted file from.
+        pub fn find_by_signature(&self, signature: &str) -> Result<Vec<Entry>> {
             let mut statement = self.connection.prepare(
-                "SELECT SUM(size) FROM entries"
+                format!("SELECT
+                        {}
+                    FROM entries
+                    WHERE signature = ?1 AND link_target IS NULL
+                    ORDER BY root, path", ENTRY_COLUMNS).as_str()
             )?;
-            let count = statement.query_row([], |row: &Row| -> rusqlite::Result<u64> {
-                row.get(0)
-            }).unwrap();
+            let entry_iter = statement.query_map([signature], ROW_TO_ENTRY)?;
 
-            Ok(count)
+            collect_rows(entry_iter)
         }
 
-        // pub fn select_all(&self) {
-        //     let mut statement = self.connection.prepare(
-        //         "SELECT
-        //                 path,
-        //                 abspath,
-        //                 basename,
-        //                 dirname,
-        //                 signature,
-        //                 size,
-        //                 timestamp,
-        //                 updated
-        //             FROM entries"
-        //     )?;
-        //     let entry_iter = statement.query_map([], ROW_TO_ENTRY)?;
-        //     for entry in entry_iter {
-        //         println!("Found entry {:?}", entry.unwrap());
-        //     }
-        // }
+        /// Records byte ranges of a file which could not be read, keeping when each was first seen.
+        pub fn add_bad_ranges(&self, key: &str, ranges: &[Range<u64>], now_timestamp: u64) -> Result<()> {
+            let mut statement = self.connection.prepare(
+                "INSERT INTO bad_ranges (root, path, start, end, first_seen, last_seen) VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+                    ON CONFLICT(root, path, start) DO UPDATE SET
+                        end = MAX(end, excluded.end),
+                        last_seen = excluded.last_seen"
+            )?;
+            for range in ranges {
+                statement.execute((self.root()?, key, range.start, range.end, now_timestamp))?;
+            }
+            Ok(())
+        }
 
-        pub fn select_all_paths(&self) -> Result<Vec<String>> {
+        /// Gets the byte ranges of a file which could not be read by any run, ordered by offset.
+        pub fn get_bad_ranges(&self, key: &str) -> Result<Vec<Range<u64>>> {
+            if !self.has_column("main", "bad_ranges", "path")? {
+                return Ok(Vec::new()); // created by an older version.
+            }
             let mut statement = self.connection.prepare(
-                "SELECT abspath FROM entries"
+                "SELECT start, end FROM bad_ranges WHERE root = ?1 AND path = ?2 ORDER BY start"
             )?;
 
-            let result_iter = statement.query_map([], |row: &Row| {
-                row.get(0)
-            })?.map(|x| { x.unwrap() });
+            let result_iter = statement.query_map([self.root()?, key], |row: &Row| {
+                Ok(row.get(0)? .. row.get(1)?)
+            })?;
+            collect_rows(result_iter)
+        }
 
-            Ok(Vec::from_iter(result_iter))
+        /// Records a failure to read a file or directory during a run.
+        pub fn add_error(&self, run_id: i64, key: &str, error: &std::io::Error, offset: Option<u64>, now_timestamp: u64) -> Result<()> {
+            self.connection.execute(
+                "INSERT INTO errors (run, root, path, kind, errno, offset, message, time) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                (run_id, self.root()?, key, format!("{:?}", error.kind()), error.raw_os_error(), offset, error.to_string(), now_timestamp),
+            )?;
+            Ok(())
         }
 
-        /// Compares the first and second databases to find any files missing in either one.
+        /// Finds files which failed to be read at least the given number of times, most failing first.
         ///
-        /// Returns a tuple of missing paths in each respective database.
-        ///
-        /// Paths included in the first vector are ones that exists in the second database, but not
-        /// in the first database, and vice versa for the second vector.
-        pub fn find_missing(&self) -> Result<(Vec<String>, Vec<String>)> {
+        /// Covers all roots, unless one is selected.
+        pub fn find_failing_files(&self, min_count: u64) -> Result<Vec<FailingFile>> {
+            if !self.has_column("main", "errors", "path")? {
+                return Ok(Vec::new()); // created by an older version.
+            }
             let mut statement = self.connection.prepare(
-                "SELECT
-                        main.entries.path,
-                        second.entries.path
-                    FROM main.entries
-                    LEFT JOIN second.entries ON main.entries.path = second.entries.path
-                    WHERE
-                        second.entries.path IS NULL
-                    UNION
-                    SELECT
-                        main.entries.path,
-                        second.entries.path
-                    FROM second.entries
-                    LEFT JOIN main.entries ON second.entries.path = main.entries.path
-                    WHERE
-                        main.entries.path IS NULL"
+                "SELECT errors.root, errors.path, counts.count, counts.first_failed, errors.time, errors.kind,
+                        errors.errno, errors.offset, errors.message
+                    FROM errors
+                    JOIN (
+                        SELECT root, path, COUNT(*) AS count, MIN(time) AS first_failed, MAX(id) AS last_id
+                            FROM errors
+                            WHERE ?2 IS NULL OR root = ?2
+                            GROUP BY root, path
+                            HAVING COUNT(*) >= ?1
+                    ) AS counts ON errors.id = counts.last_id
+                    ORDER BY counts.count DESC, errors.root, errors.path"
             )?;
-            let entry_iter = statement.query_map([], |row| {
-                let first_path = get_row_value(row, 0);
-                let second_path = get_row_value(row, 1);
-                Ok((first_path, second_path))
-            })?;
 
-            let mut missing_in_first: Vec<String> = Vec::new();
-            let mut missing_in_second: Vec<String> = Vec::new();
+            let result_iter = statement.query_map((min_count, &self.root), |row: &Row| {
+                Ok(FailingFile {
+                    root: row.get(0)?,
+                    path: row.get(1)?,
+                    count: row.get(2)?,
+                    first_failed: row.get(3)?,
+                    last_failed: row.get(4)?,
+                    kind: row.get(5)?,
+                    errno: row.get(6)?,
+                    offset: row.get(7)?,
+                    message: row.get(8)?,
+                })
+            })?;
+            collect_rows(result_iter)
+        }
 
-            for entry in entry_iter {
-                let entry = entry.unwrap();
-                if entry.0.is_none() {
-                    missing_in_first.push(entry.1.unwrap());
-                } else {
-                    missing_in_second.push(entry.0.unwrap());
+        fn create_blocks_table(&self) -> Result<()> {
+            match self.connection.execute(
+                "CREATE TABLE IF NOT EXISTS blocks (
+                        root      TEXT NOT NULL,
+                        path      TEXT NOT NULL,
+                        offset    INTEGER NOT NULL,
+                        length    INTEGER NOT NULL,
+                        signature TEXT NOT NULL,
+                        PRIMARY KEY (root, path, offset)
+                    )",
+                (), // empty list of parameters.
+            ) {
+                Ok(_any) => Ok(()), // reports the changes of the previous statement.
+                Err(why) => {
+                    error!("Unexpected error during blocks table creation: {}", why);
+                    Err(why.into())
                 }
             }
-
-            Ok((missing_in_first, missing_in_second))
         }
 
-        /// Compares and finds files with the same path, but differing file content hashes.
-        pub fn compare(&self) -> Result<Vec<(String, String, String, u64, String, String, u64)>> {
+        /// Replaces the block signatures stored for an entry.
+        pub fn set_blocks(&self, key: &str, blocks: &[Block]) -> Result<()> {
+            self.connection.execute("DELETE FROM blocks WHERE root = ?1 AND path = ?2", [self.root()?, key])?;
             let mut statement = self.connection.prepare(
-                "SELECT
-                        main.entries.path,
-                        main.entries.abspath,
-                        main.entries.signature,
-                        main.entries.timestamp,
-                        second.entries.abspath,
-                        second.entries.signature,
-                        second.entries.timestamp
-                    FROM
-                        main.entries
-                    LEFT JOIN
-                        second.entries ON main.entries.path = second.entries.path
+                "INSERT INTO blocks (root, path, offset, length, signature) VALUES (?1, ?2, ?3, ?4, ?5)"
+            )?;
+            for block in blocks {
+                statement.execute((self.root()?, key, block.offset, block.length, &block.signature))?;
+            }
+            Ok(())
+        }
+
+        pub fn has_blocks(&self, key: &str) -> Result<bool> {
+            Ok(self.connection.query_row("SELECT EXISTS (SELECT 1 FROM blocks WHERE root = ?1 AND path = ?2)", [self.root()?, key], |row: &Row| {
+                row.get(0)
+            })?)
+        }
+
+        /// Gets the block signatures stored for an entry, ordered by offset.
+        pub fn get_blocks(&self, which: Option<Which>, key: &str) -> Result<Vec<Block>> {
+            let table = match which {
+                None => "blocks",
+                Some(Which::First) => "blocks",
+                Some(Which::Second) => "second_blocks"
+            };
+            // the view of the second database stands in for a missing table.
+            if table == "blocks" && !self.has_column("main", "blocks", "path")? {
+                return Ok(Vec::new()); // created by an older version.
+            }
+            let mut statement = self.connection.prepare(
+                format!("SELECT offset, length, signature
+                    FROM {}
+                    WHERE root = ?1 AND path = ?2
+                    ORDER BY offset", table).as_str()
+            )?;
+
+            let result_iter = statement.query_map([self.root_of(which)?, key], |row: &Row| {
+                Ok(Block { offset: row.get(0)?, length: row.get(1)?, signature: row.get(2)? })
+            })?;
+            collect_rows(result_iter)
+        }
+
+        /// Records the start of a run, returning its id.
+        pub fn start_run(&self, command: &str, now_timestamp: u64, read_mode: ReadMode) -> Result<i64> {
+            self.connection.execute(
+                "INSERT INTO runs (command, started, read_mode) VALUES (?1, ?2, ?3)",
+                (command, now_timestamp, read_mode.name()),
+            )?;
+            Ok(self.connection.last_insert_rowid())
+        }
+
+        pub fn finish_run(&self, id: i64, now_timestamp: u64, fallback_reads: u64, errors: u64) -> Result<()> {
+            match self.connection.execute(
+                "UPDATE runs SET finished = ?2, fallback_reads = ?3, errors = ?4 WHERE id = ?1",
+                (id, now_timestamp, fallback_reads, errors),
+            )? {
+                1 => Ok(()),
+                updates => Err(unexpected_changes("when finishing run", updates)),
+            }
+        }
+
+        pub fn get_last_run(&self) -> Result<Option<Run>> {
+            if !self.has_column("main", "runs", "id")? {
+                return Ok(None); // created by an older version.
+            }
+            let found_run = self.connection.query_row(
+                "SELECT command, started, finished, read_mode, fallback_reads, errors
+                    FROM runs
+                    ORDER BY id DESC
+                    LIMIT 1",
+                [],
+                |row: &Row| {
+                    Ok(Run {
+                        command: row.get(0)?,
+                        started: row.get(1)?,
+                        finished: row.get(2)?,
+                        read_mode: row.get(3)?,
+                        fallback_reads: row.get(4)?,
+                        errors: row.get(5)?,
+                    })
+                });
+            match found_run {
+                Ok(run) => Ok(Some(run)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+                Err(any) => Err(any.into()),
+            }
+        }
+
+        pub fn add_entry(&self, entry: &Entry) -> Result<()> {
+            match self.connection.execute(
+                "INSERT INTO entries
+                        (path, abspath, basename, dirname, signature, size, timestamp, updated, link_target, device, inode,
+                            timestamp_nsec, ctime, ctime_nsec, root, added, hashed)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?8, ?16)
+                    ON CONFLICT(root, path) DO UPDATE SET
+                        abspath = ?2,
+                        basename = ?3,
+                        dirname = ?4,
+                        signature = ?5,
+                        size = ?6,
+                        timestamp = ?7,
+                        updated = ?8,
+                        link_target = ?9,
+                        device = ?10,
+                        inode = ?11,
+                        timestamp_nsec = ?12,
+                        ctime = ?13,
+                        ctime_nsec = ?14,
+                        hashed = ?16,
+                        verify_status = NULL",
+                (
+                    &entry.path, &entry.abspath, &entry.basename, &entry.dirname,
+                    &entry.signature, &entry.size, &entry.timestamp, &entry.updated,
+                    &entry.link_target, &entry.device, &entry.inode,
+                    &entry.timestamp_nsec, &entry.ctime, &entry.ctime_nsec, self.root()?, &entry.hashed),
+            ) {
+                Ok(_any) => Ok(()),
+                Err(why) => {
+                    error!("Failed to add entry to table -> {}", why);
+                    Err(why.into())
+                }
+            }
+        }
+
+        pub fn get_entry(&self, key: &str) -> Result<Entry, DatabaseError> {
+            let mut statement = self.connection.prepare(
+                format!("SELECT
+                        {}
+                    FROM entries
+                    WHERE root = ?1 AND path = ?2", ENTRY_COLUMNS).as_str()
+            )?;
+
+            let found_entry = statement.query_row([self.root()?, key], ROW_TO_ENTRY);
+            match found_entry {
+                Ok(entry) => Ok(entry),
+                Err(rusqlite::Error::QueryReturnedNoRows) => Err(DatabaseError::EntryNotFound),
+                Err(any) => Err(any.into()),
+            }
+        }
+
+        /// Removes an entry from the index, keeping a record of it in the deleted entries.
+        pub fn remove_entry(&self, key: &str, now_timestamp: u64) -> Result<(), DatabaseError> {
+            self.connection.execute(
+                "INSERT INTO deleted_entries (root, path, abspath, signature, size, timestamp, link_target, deleted)
+                    SELECT root, path, abspath, signature, size, timestamp, link_target, ?3
+                    FROM entries
+                    WHERE root = ?1 AND path = ?2",
+                (self.root()?, key, now_timestamp),
+            )?;
+            self.forget_entry(key)
+        }
+
+        /// Removes an entry from the index without keeping a record of it, such as when it moved.
+        fn forget_entry(&self, key: &str) -> Result<(), DatabaseError> {
+            let mut statement = self.connection.prepare(
+                "DELETE
+                    FROM entries
+                    WHERE root = ?1 AND path = ?2"
+            )?;
+
+            match statement.execute([self.root()?, key]) {
+                Ok(1) => {
+                    self.set_blocks(key, &[])?;
+                    Ok(())
+                },
+                Ok(updates) => Err(unexpected_changes("during entry removal", updates)),
+                Err(why) => {
+                    error!("Unexpected error during entry removal: {}", why);
+                    Err(why.into())
+                }
+            }
+        }
+
+        /// Finds entries of the selected root with the given device and inode.
+        pub fn find_by_inode(&self, device: u64, inode: u64) -> Result<Vec<Entry>> {
+            let mut statement = self.connection.prepare(
+                format!("SELECT
+                        {}
+                    FROM entries
+                    WHERE device = ?1 AND inode = ?2 AND root = ?3
+                    ORDER BY path", ENTRY_COLUMNS).as_str()
+            )?;
+            let entry_iter = statement.query_map((device, inode, self.root()?), ROW_TO_ENTRY)?;
+
+            collect_rows(entry_iter)
+        }
+
+        /// Finds entries of the selected root first added at the given time, with the same contents
+        /// and modification time as the given entry, which have not been recorded as moved to yet.
+        pub fn find_added_copies(&self, entry: &Entry, added_timestamp: u64) -> Result<Vec<Entry>> {
+            let mut statement = self.connection.prepare(
+                format!("SELECT
+                        {}
+                    FROM entries
+                    WHERE root = ?1 AND signature = ?2 AND size = ?3 AND timestamp = ?4 AND link_target IS ?5
+                        AND added = ?6 AND path != ?7
+                        -- moves are recorded with the time of the run.
+                        AND path NOT IN (SELECT to_path FROM moves WHERE root = ?1 AND time = ?6)
+                    ORDER BY path", ENTRY_COLUMNS).as_str()
+            )?;
+            let entry_iter = statement.query_map(
+                (self.root()?, &entry.signature, entry.size, entry.timestamp, &entry.link_target, added_timestamp, &entry.path),
+                ROW_TO_ENTRY,
+            )?;
+
+            collect_rows(entry_iter)
+        }
+
+        /// Gives an entry the path of the given entry, keeping its signatures and verification.
+        pub fn rename_entry(&self, from_key: &str, to: &Entry) -> Result<(), DatabaseError> {
+            let updates = self.connection.execute(
+                "UPDATE entries
+                    SET path = ?3, abspath = ?4, basename = ?5, dirname = ?6, ctime = ?7, ctime_nsec = ?8
+                    WHERE root = ?1 AND path = ?2",
+                (self.root()?, from_key, &to.path, &to.abspath, &to.basename, &to.dirname, &to.ctime, &to.ctime_nsec),
+            )?;
+            match updates {
+                1 => {},
+                0 => return Err(DatabaseError::EntryNotFound),
+                updates => return Err(unexpected_changes("during entry rename", updates)),
+            }
+            self.connection.execute("UPDATE blocks SET path = ?3 WHERE root = ?1 AND path = ?2", (self.root()?, from_key, &to.path))?;
+            Ok(())
+        }
+
+        /// Records that a file moved within the selected root.
+        ///
+        /// When the entry of the old path still exists, it is removed without keeping a record of
+        /// a deleted file, as the entry of the new path already has the same contents.
+        pub fn add_move(&self, run_id: i64, from_key: &str, to_key: &str, method: MoveMethod, now_timestamp: u64) -> Result<(), DatabaseError> {
+            if self.get_entry(from_key).is_ok() {
+                self.forget_entry(from_key)?;
+            }
+            self.connection.execute(
+                "INSERT INTO moves (run, root, from_path, to_path, method, time) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                (run_id, self.root()?, from_key, to_key, method.name(), now_timestamp),
+            )?;
+            Ok(())
+        }
+
+        fn create_moves_table(&self) -> Result<()> {
+            match self.connection.execute(
+                "CREATE TABLE IF NOT EXISTS moves (
+                        id        INTEGER PRIMARY KEY,
+                        run       INTEGER NOT NULL,
+                        root      TEXT NOT NULL,
+                        from_path TEXT NOT NULL,
+                        to_path   TEXT NOT NULL,
+                        method    TEXT NOT NULL,
+                        time      INTEGER NOT NULL
+                    )",
+                (), // empty list of parameters.
+            ) {
+                Ok(_any) => Ok(()), // reports the changes of the previous statement.
+                Err(why) => {
+                    error!("Unexpected error during moves table creation: {}", why);
+                    Err(why.into())
+                }
+            }
+        }
+
+        /// Counts the entries of the selected root, or of all roots when none is selected.
+        pub fn get_count(&self, which: Option<Which>) -> Result<u64> {
+            let table_name = match which {
+                None => "entries",
+                Some(Which::First) => "entries",
+                Some(Which::Second) => "second_entries"
+            };
+            let mut statement = self.connection.prepare(
+                format!("SELECT COUNT(1) FROM {} WHERE ?1 IS NULL OR root = ?1", table_name).as_str()
+            )?;
+            let count = statement.query_row([self.selected_root(which)], |row: &Row| -> rusqlite::Result<u64> {
+                row.get(0)
+            })?;
+
+            Ok(count)
+        }
+
+        /// Sums the size of the entries of the selected root, or of all roots when none is selected.
+        pub fn get_size(&self) -> Result<u64> {
+            let mut statement = self.connection.prepare(
+                "SELECT COALESCE(SUM(size), 0) FROM entries WHERE ?1 IS NULL OR root = ?1"
+            )?;
+            let count = statement.query_row([&self.root], |row: &Row| -> rusqlite::Result<u64> {
+                row.get(0)
+            })?;
+
+            Ok(count)
+        }
+
+        /// Selects all entries along with the name of their root, starting from the least recently
+        /// verified ones. Covers all roots, unless one is selected.
+        ///
+        /// Entries which have never been verified come first.
+        pub fn select_entries_by_last_verified(&self) -> Result<Vec<(String, Entry)>> {
+            let mut statement = self.connection.prepare(
+                format!("SELECT
+                        {}, root
+                    FROM entries
+                    WHERE ?1 IS NULL OR root = ?1
+                    ORDER BY last_verified, root, path", ENTRY_COLUMNS).as_str()
+            )?;
+            let entry_iter = statement.query_map([&self.root], |row: &Row| {
+                Ok((row.get(15)?, ROW_TO_ENTRY(row)?))
+            })?;
+
+            collect_rows(entry_iter)
+        }
+
+        pub fn set_last_verified(&self, key: &str, now_timestamp: u64, status: VerifyStatus) -> Result<(), DatabaseError> {
+            let mut statement = self.connection.prepare(
+                "UPDATE entries
+                    SET last_verified = ?2, verify_status = ?3
+                    WHERE path = ?1 AND root = ?4"
+            )?;
+
+            match statement.execute((key, now_timestamp, status.name(), self.root()?)) {
+                Ok(1) => Ok(()),
+                Ok(0) => Err(DatabaseError::EntryNotFound),
+                Ok(updates) => Err(unexpected_changes("during last verified update", updates)),
+                Err(why) => {
+                    error!("Unexpected error during last verified update: {}", why);
+                    Err(why.into())
+                }
+            }
+        }
+
+        /// Overwrites the stored signature of an existing entry, which is then no longer taken as verified.
+        pub fn update_signature(&self, key: &str, signature: &str, now_timestamp: u64) -> Result<(), DatabaseError> {
+            let mut statement = self.connection.prepare(
+                "UPDATE entries
+                    SET signature = ?2, updated = ?3, last_verified = NULL, verify_status = NULL
+                    WHERE path = ?1 AND root = ?4"
+            )?;
+
+            match statement.execute((key, signature, now_timestamp, self.root()?)) {
+                Ok(1) => Ok(()),
+                Ok(0) => Err(DatabaseError::EntryNotFound),
+                Ok(updates) => Err(unexpected_changes("during signature update", updates)),
+                Err(why) => {
+                    error!("Unexpected error during signature update: {}", why);
+                    Err(why.into())
+                }
+            }
+        }
+
+        /// Counts entries by the result of their last verification, leaving out verified entries.
+        /// Covers all roots, unless one is selected.
+        pub fn count_by_verify_status(&self) -> Result<Vec<(String, u64)>> {
+            if !self.has_column("main", "entries", "verify_status")? {
+                return Ok(Vec::new()); // created by an older version.
+            }
+            let mut statement = self.connection.prepare(
+                "SELECT verify_status, COUNT(*)
+                    FROM entries
+                    WHERE verify_status IS NOT NULL AND verify_status != 'verified' AND (?1 IS NULL OR root = ?1)
+                    GROUP BY verify_status
+                    ORDER BY verify_status"
+            )?;
+
+            let result_iter = statement.query_map([&self.root], |row: &Row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+            collect_rows(result_iter)
+        }
+
+        pub fn select_all_paths(&self) -> Result<Vec<String>> {
+            let mut statement = self.connection.prepare(
+                "SELECT abspath FROM entries WHERE root = ?1"
+            )?;
+
+            let result_iter = statement.query_map([self.root()?], |row: &Row| {
+                row.get(0)
+            })?;
+            collect_rows(result_iter)
+        }
+
+        /// Gives the keys of the entry with the key, and of the entries under it when it is a directory.
+        pub fn select_paths_under(&self, key: &str) -> Result<Vec<String>> {
+            let mut statement = self.connection.prepare(
+                "SELECT path FROM entries
+                    WHERE root = ?1 AND (path = ?2 OR substr(path, 1, length(?2) + 1) = ?2 || '/')"
+            )?;
+
+            let result_iter = statement.query_map([self.root()?, key], |row: &Row| {
+                row.get(0)
+            })?;
+            collect_rows(result_iter)
+        }
+
+        /// Compares the selected roots of the first and second databases to find any files missing in
+        /// either one.
+        pub fn find_missing(&self) -> Result<MissingFiles> {
+            let mut statement = self.connection.prepare(
+                "SELECT
+                        entries.path,
+                        second_entries.path
+                    FROM entries
+                    LEFT JOIN second_entries ON entries.path = second_entries.path AND second_entries.root = ?2
+                    WHERE
+                        entries.root = ?1
+                        AND second_entries.path IS NULL
+                    UNION
+                    SELECT
+                        entries.path,
+                        second_entries.path
+                    FROM second_entries
+                    LEFT JOIN entries ON second_entries.path = entries.path AND entries.root = ?1
                     WHERE
-                        second.entries.path IS NOT NULL
-                        AND main.entries.signature != second.entries.signature"
-            )?;
-            let entry_iter = statement.query_map([], |row| {
-                let path: String = row.get(0).unwrap();
-                let first_abspath: String = row.get(1).unwrap();
-                let first_sig: String = row.get(2).unwrap();
-                let first_timestamp: u64 = row.get(3).unwrap();
-                let second_abspath: String = row.get(4).unwrap();
-                let second_sig: String = row.get(5).unwrap();
-                let second_timestamp: u64 = row.get(6).unwrap();
-                Ok((path, first_abspath, first_sig, first_timestamp, second_abspath, second_sig, second_timestamp))
+                        second_entries.root = ?2
+                        AND entries.path IS NULL"
+            )?;
+            let entry_iter = statement.query_map([self.root_of(Some(Which::First))?, self.root_of(Some(Which::Second))?], |row| {
+                let first_path = get_row_value(row, 0);
+                let second_path = get_row_value(row, 1);
+                Ok((first_path, second_path))
             })?;
 
-            Ok(Vec::from_iter(entry_iter.map(|x| { x.unwrap() })))
+            let mut missing = MissingFiles::default();
+            for entry in entry_iter {
+                match entry? {
+                    (None, Some(second_path)) => missing.missing_in_first.push(second_path),
+                    (Some(first_path), _) => missing.missing_in_second.push(first_path),
+                    (None, None) => {},
+                }
+            }
+
+            Ok(missing)
         }
 
-        /// Find possible duplicate files in the index.
-        /// 
-        /// Returns groups of files with the same hash/signature in a multimap, where the key is the signature and values are the index entries.
-        pub fn find_dupes(&self) -> Result<MultiMap<String, Entry>> {
+        /// Compares the selected roots and finds files with the same path, but differing file content hashes,
+        /// locating the differing byte ranges from block signatures.
+        ///
+        /// Refuses to compare databases using different hash algorithms, as every file would be
+        /// reported as different.
+        pub fn compare(&self) -> Result<Vec<Difference>, DatabaseError> {
+            let first_algorithm = self.get_metadata(Some(Which::First))?.algorithm;
+            let second_algorithm = self.get_metadata(Some(Which::Second))?.algorithm;
+            if first_algorithm != second_algorithm {
+                return Err(DatabaseError::AlgorithmMismatch(first_algorithm, second_algorithm));
+            }
+
             let mut statement = self.connection.prepare(
                 "SELECT
-                        path,
-                        abspath,
-                        basename,
-                        dirname,
-                        signature,
-                        size,
-                        timestamp,
-                        updated
+                        entries.path,
+                        entries.abspath,
+                        entries.signature,
+                        entries.timestamp,
+                        second_entries.abspath,
+                        second_entries.signature,
+                        second_entries.timestamp
+                    FROM
+                        entries
+                    LEFT JOIN
+                        second_entries ON entries.path = second_entries.path AND second_entries.root = ?2
+                    WHERE
+                        entries.root = ?1
+                        AND second_entries.path IS NOT NULL
+                        AND entries.signature != second_entries.signature"
+            )?;
+            let entry_iter = statement.query_map([self.root_of(Some(Which::First))?, self.root_of(Some(Which::Second))?], |row| {
+                Ok(Difference {
+                    path: row.get(0)?,
+                    first_abspath: row.get(1)?,
+                    first_signature: row.get(2)?,
+                    first_timestamp: row.get(3)?,
+                    second_abspath: row.get(4)?,
+                    second_signature: row.get(5)?,
+                    second_timestamp: row.get(6)?,
+                    differing_ranges: None,
+                })
+            })?;
+
+            let mut differences: Vec<Difference> = entry_iter.collect::<rusqlite::Result<_>>()?;
+            for difference in &mut differences {
+                let first_blocks = self.get_blocks(Some(Which::First), &difference.path)?;
+                let second_blocks = self.get_blocks(Some(Which::Second), &difference.path)?;
+                difference.differing_ranges = model::differing_ranges(&first_blocks, &second_blocks);
+            }
+            Ok(differences)
+        }
+
+        /// Find possible duplicate files in the index.
+        ///
+        /// Returns groups of files with the same hash/signature, ordered by signature.
+        /// Hard links to the same file are not counted as duplicates of each other.
+        /// Duplicates are found across all roots, unless one is selected.
+        pub fn find_dupes(&self) -> Result<Vec<DupeGroup>> {
+            let mut statement = self.connection.prepare(
+                format!("SELECT
+                        {}
                     FROM entries
-                    WHERE signature IN (
+                    WHERE link_target IS NULL AND (?1 IS NULL OR root = ?1) AND signature IN (
                         SELECT
                             signature
                         FROM entries
+                        WHERE link_target IS NULL AND (?1 IS NULL OR root = ?1)
                         GROUP BY signature
+                        HAVING COUNT(DISTINCT COALESCE(device || ':' || inode, root || '/' || path)) > 1
+                    )
+                    ORDER BY signature, root, path", ENTRY_COLUMNS).as_str()
+            )?;
+            let entry_iter = statement.query_map([&self.root], ROW_TO_ENTRY)?;
+
+            let mut dupe_groups: Vec<DupeGroup> = Vec::new();
+            for entry in entry_iter {
+                let entry = entry?;
+                match dupe_groups.last_mut() {
+                    Some(group) if group.signature == entry.signature => group.entries.push(entry),
+                    _ => dupe_groups.push(DupeGroup { signature: entry.signature.clone(), entries: vec![entry] }),
+                }
+            }
+
+            Ok(dupe_groups)
+        }
+
+        /// Find hard links in the index.
+        ///
+        /// Returns groups of files sharing the same inode, ordered by device and inode number.
+        /// Hard links are found across all roots, unless one is selected.
+        pub fn find_hardlinks(&self) -> Result<Vec<HardlinkGroup>> {
+            let mut statement = self.connection.prepare(
+                format!("SELECT
+                        {}
+                    FROM entries
+                    WHERE (?1 IS NULL OR root = ?1) AND (device, inode) IN (
+                        SELECT
+                            device,
+                            inode
+                        FROM entries
+                        WHERE inode IS NOT NULL AND (?1 IS NULL OR root = ?1)
+                        GROUP BY device, inode
                         HAVING COUNT(*) > 1
                     )
-                    ORDER BY signature"
+                    ORDER BY device, inode, root, path", ENTRY_COLUMNS).as_str()
             )?;
-            let entry_iter = statement.query_map([], ROW_TO_ENTRY)?;
+            let entry_iter = statement.query_map([&self.root], ROW_TO_ENTRY)?;
 
-            let mut dupe_files = MultiMap::new();
+            let mut hardlinks: Vec<HardlinkGroup> = Vec::new();
             for entry in entry_iter {
                 let entry = entry?;
-                dupe_files.insert(entry.signature.clone(), entry);
+                let (device, inode) = (entry.device.unwrap_or_default(), entry.inode.unwrap_or_default());
+                match hardlinks.last_mut() {
+                    Some(group) if group.device == device && group.inode == inode => group.entries.push(entry),
+                    _ => hardlinks.push(HardlinkGroup { device, inode, entries: vec![entry] }),
+                }
             }
 
-            Ok(dupe_files)
+            Ok(hardlinks)
         }
     }
 
+    /// Names a root after the last component of its path, such as `photos` for `/mnt/photos`.
+    fn default_root_name(path: &str) -> String {
+        Path::new(path).file_name().map_or(path.to_string(), |name| name.to_string_lossy().to_string())
+    }
+
     fn get_row_value(row: &Row, index: usize) -> Option<String> {
-        match row.get(index) {
-            Ok(any) => Some(any),
-            Err(_) => None
-        }
+        row.get(index).ok()
     }
 }
 
@@ -483,8 +1599,8 @@ mod dupe_tests {
     #[test]
     fn has_dupes() {
         let connection = Connection::open(":memory:").unwrap();
-        let database = Database::new(&connection);
-        database.init_for("/path/to", 1000, false).unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for("/path/to", None, 1000, false, None).unwrap();
 
         let entry1 = &Entry::new_simple(
             "to/file1", "/path/to/file1", "file1", "/path/to", "00deadbeef", 100, 100, 100
@@ -496,23 +1612,25 @@ mod dupe_tests {
             "to/file3", "/path/to/file3", "file3", "/path/to", "00cafecafe", 100, 100, 100
         );
 
-        database.add_entry(entry1);
-        database.add_entry(entry2);
-        database.add_entry(entry3);
+        database.add_entry(entry1).unwrap();
+        database.add_entry(entry2).unwrap();
+        database.add_entry(entry3).unwrap();
         assert_eq!(3, database.get_count(None).unwrap());
 
         let dupe_files = database.find_dupes().unwrap();
-        assert_eq!(2, dupe_files.len());
-        let entries = dupe_files.get_vec("00deadbeef").unwrap();
-        assert_eq!(entry1.path, entries.get(0).unwrap().path);
+        assert_eq!(1, dupe_files.len());
+        assert_eq!("00deadbeef", dupe_files[0].signature);
+        let entries = &dupe_files[0].entries;
+        assert_eq!(2, entries.len());
+        assert_eq!(entry1.path, entries.first().unwrap().path);
         assert_eq!(entry2.path, entries.get(1).unwrap().path);
     }
 
     #[test]
     fn has_triple_dupes() {
         let connection = Connection::open(":memory:").unwrap();
-        let database = Database::new(&connection);
-        database.init_for("/path/to", 1000, false).unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for("/path/to", None, 1000, false, None).unwrap();
 
         let entry1 = &Entry::new_simple(
             "to/file1", "/path/to/file1", "file1", "/path/to", "00deadbeef", 100, 100, 100
@@ -524,15 +1642,17 @@ mod dupe_tests {
             "to/file3", "/path/to/file3", "file3", "/path/to", "00deadbeef", 100, 100, 100
         );
 
-        database.add_entry(entry1);
-        database.add_entry(entry2);
-        database.add_entry(entry3);
+        database.add_entry(entry1).unwrap();
+        database.add_entry(entry2).unwrap();
+        database.add_entry(entry3).unwrap();
         assert_eq!(3, database.get_count(None).unwrap());
 
         let dupe_files = database.find_dupes().unwrap();
-        assert_eq!(3, dupe_files.len());
-        let entries = dupe_files.get_vec("00deadbeef").unwrap();
-        assert_eq!(entry1.path, entries.get(0).unwrap().path);
+        assert_eq!(1, dupe_files.len());
+        assert_eq!("00deadbeef", dupe_files[0].signature);
+        let entries = &dupe_files[0].entries;
+        assert_eq!(3, entries.len());
+        assert_eq!(entry1.path, entries.first().unwrap().path);
         assert_eq!(entry2.path, entries.get(1).unwrap().path);
         assert_eq!(entry3.path, entries.get(2).unwrap().path);
     }
@@ -540,8 +1660,8 @@ mod dupe_tests {
     #[test]
     fn has_no_dupes() {
         let connection = Connection::open(":memory:").unwrap();
-        let database = Database::new(&connection);
-        database.init_for("/path/to", 1000, false).unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for("/path/to", None, 1000, false, None).unwrap();
 
         let entry1 = &Entry::new_simple(
             "to/file1", "/path/to/file1", "file1", "/path/to", "00deadbeef", 100, 100, 100
@@ -553,12 +1673,407 @@ mod dupe_tests {
             "to/file3", "/path/to/file3", "file3", "/path/to", "00cafecafe", 100, 100, 100
         );
 
-        database.add_entry(entry1);
-        database.add_entry(entry2);
-        database.add_entry(entry3);
+        database.add_entry(entry1).unwrap();
+        database.add_entry(entry2).unwrap();
+        database.add_entry(entry3).unwrap();
         assert_eq!(3, database.get_count(None).unwrap());
 
         let dupe_files = database.find_dupes().unwrap();
         assert_eq!(0, dupe_files.len());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn hardlinks_are_not_dupes() {
+        let connection = Connection::open(":memory:").unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for("/path/to", None, 1000, false, None).unwrap();
+
+        let entry1 = &Entry { device: Some(1), inode: Some(10), ..Entry::new_simple(
+            "to/file1", "/path/to/file1", "file1", "/path/to", "00deadbeef", 100, 100, 100
+        ) };
+        let entry2 = &Entry { device: Some(1), inode: Some(10), ..Entry::new_simple(
+            "to/file2", "/path/to/file2", "file2", "/path/to", "00deadbeef", 100, 100, 100
+        ) };
+        let entry3 = &Entry { device: Some(1), inode: Some(20), ..Entry::new_simple(
+            "to/file3", "/path/to/file3", "file3", "/path/to", "00cafecafe", 100, 100, 100
+        ) };
+
+        database.add_entry(entry1).unwrap();
+        database.add_entry(entry2).unwrap();
+        database.add_entry(entry3).unwrap();
+
+        let dupe_files = database.find_dupes().unwrap();
+        assert_eq!(0, dupe_files.len());
+
+        let hardlinks = database.find_hardlinks().unwrap();
+        assert_eq!(1, hardlinks.len());
+        assert_eq!((1, 10), (hardlinks[0].device, hardlinks[0].inode));
+        let entries = &hardlinks[0].entries;
+        assert_eq!(entry1.path, entries.first().unwrap().path);
+        assert_eq!(entry2.path, entries.get(1).unwrap().path);
+    }
+}
+
+#[cfg(test)]
+mod signature_tests {
+    use rusqlite::Connection;
+    use crate::Database;
+    use crate::db::db::DatabaseError;
+    use crate::model::model::{Entry, VerifyStatus};
+
+    #[test]
+    fn update_signature() {
+        let connection = Connection::open(":memory:").unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for("/path/to", None, 1000, false, None).unwrap();
+
+        let entry1 = &Entry::new_simple(
+            "to/file1", "/path/to/file1", "file1", "/path/to", "00deadbeef", 100, 100, 100
+        );
+        database.add_entry(entry1).unwrap();
+        database.set_last_verified("to/file1", 150, VerifyStatus::Mismatch).unwrap();
+
+        database.update_signature("to/file1", "00cafecafe", 200).unwrap();
+
+        let last_verified: Option<u64> = connection.query_row(
+            "SELECT last_verified FROM entries WHERE path = 'to/file1'", [], |row| row.get(0)
+        ).unwrap();
+        assert_eq!(None, last_verified);
+        assert!(database.count_by_verify_status().unwrap().is_empty());
+
+        let entries = database.select_entries_by_last_verified().unwrap();
+        assert_eq!(1, entries.len());
+        assert_eq!("to", entries[0].0);
+        assert_eq!("00cafecafe", entries[0].1.signature);
+        assert_eq!(200, entries[0].1.updated);
+        assert_eq!(100, entries[0].1.timestamp);
+    }
+
+    #[test]
+    fn update_signature_for_missing_entry() {
+        let connection = Connection::open(":memory:").unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for("/path/to", None, 1000, false, None).unwrap();
+
+        let result = database.update_signature("to/file1", "00cafecafe", 200);
+        assert!(matches!(result, Err(DatabaseError::EntryNotFound)));
+    }
+}
+
+#[cfg(test)]
+mod last_verified_tests {
+    use rusqlite::Connection;
+    use crate::Database;
+    use crate::model::model::{Entry, VerifyStatus};
+
+    #[test]
+    fn least_recently_verified_first() {
+        let connection = Connection::open(":memory:").unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for("/path/to", None, 1000, false, None).unwrap();
+
+        let entry1 = &Entry::new_simple(
+            "to/file1", "/path/to/file1", "file1", "/path/to", "00deadbeef", 100, 100, 100
+        );
+        let entry2 = &Entry::new_simple(
+            "to/file2", "/path/to/file2", "file2", "/path/to", "0000000000", 100, 100, 100
+        );
+        let entry3 = &Entry::new_simple(
+            "to/file3", "/path/to/file3", "file3", "/path/to", "00cafecafe", 100, 100, 100
+        );
+
+        database.add_entry(entry1).unwrap();
+        database.add_entry(entry2).unwrap();
+        database.add_entry(entry3).unwrap();
+        database.set_last_verified("to/file1", 300, VerifyStatus::Verified).unwrap();
+        database.set_last_verified("to/file3", 200, VerifyStatus::Flapping).unwrap();
+
+        let entries = database.select_entries_by_last_verified().unwrap();
+        assert_eq!(entry2.path, entries[0].1.path);
+        assert_eq!(entry3.path, entries[1].1.path);
+        assert_eq!(entry1.path, entries[2].1.path);
+
+        let statuses = database.count_by_verify_status().unwrap();
+        assert_eq!(vec![("flapping".to_string(), 1)], statuses);
+    }
+}
+
+#[cfg(test)]
+mod run_tests {
+    use rusqlite::Connection;
+    use crate::Database;
+    use crate::hashing::hashing::ReadMode;
+
+    #[test]
+    fn last_run() {
+        let connection = Connection::open(":memory:").unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for("/path/to", None, 1000, false, None).unwrap();
+        assert!(database.get_last_run().unwrap().is_none());
+
+        let first = database.start_run("index", 1000, ReadMode::Cached).unwrap();
+        database.finish_run(first, 1100, 0, 0).unwrap();
+        database.start_run("scrub", 1200, ReadMode::Direct).unwrap();
+
+        let run = database.get_last_run().unwrap().unwrap();
+        assert_eq!("scrub", run.command);
+        assert_eq!(1200, run.started);
+        assert_eq!(None, run.finished);
+        assert_eq!("direct", run.read_mode);
+    }
+}
+
+#[cfg(test)]
+mod error_tests {
+    use std::io::Error;
+    use std::ops::Range;
+    use rusqlite::Connection;
+    use crate::Database;
+    use crate::hashing::hashing::ReadMode;
+
+    #[test]
+    fn repeatedly_failing_files() {
+        let connection = Connection::open(":memory:").unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for("/path/to", None, 1000, false, None).unwrap();
+
+        let first = database.start_run("index", 1000, ReadMode::Cached).unwrap();
+        database.add_error(first, "to/file1", &Error::from_raw_os_error(libc::EACCES), None, 1000).unwrap();
+        database.add_error(first, "to/file2", &Error::from_raw_os_error(libc::EIO), Some(4096), 1000).unwrap();
+        let second = database.start_run("scrub", 2000, ReadMode::Direct).unwrap();
+        database.add_error(second, "to/file2", &Error::from_raw_os_error(libc::EIO), Some(8192), 2000).unwrap();
+
+        let failing = database.find_failing_files(2).unwrap();
+        assert_eq!(1, failing.len());
+        assert_eq!("to", failing[0].root);
+        assert_eq!("to/file2", failing[0].path);
+        assert_eq!(2, failing[0].count);
+        assert_eq!(1000, failing[0].first_failed);
+        assert_eq!(2000, failing[0].last_failed);
+        assert_eq!(Some(libc::EIO), failing[0].errno);
+        assert_eq!(Some(8192), failing[0].offset);
+
+        assert_eq!(2, database.find_failing_files(1).unwrap().len());
+    }
+
+    #[test]
+    fn bad_ranges() {
+        let connection = Connection::open(":memory:").unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for("/path/to", None, 1000, false, None).unwrap();
+
+        database.add_bad_ranges("to/file1", &[4096 .. 8192, 16384 .. 20480], 1000).unwrap();
+        database.add_bad_ranges("to/file1", &[Range { start: 4096, end: 12288 }], 2000).unwrap();
+
+        let ranges = database.get_bad_ranges("to/file1").unwrap();
+        assert_eq!(vec![4096 .. 12288, 16384 .. 20480], ranges);
+        assert!(database.get_bad_ranges("to/file2").unwrap().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod root_tests {
+    use rusqlite::Connection;
+    use crate::Database;
+    use crate::db::db::DatabaseError;
+    use crate::model::model::Entry;
+
+    #[test]
+    fn dupes_across_roots() {
+        let connection = Connection::open(":memory:").unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for("/path/to", None, 1000, false, None).unwrap();
+        let entry1 = &Entry::new_simple(
+            "to/file1", "/path/to/file1", "file1", "/path/to", "00deadbeef", 100, 100, 100
+        );
+        database.add_entry(entry1).unwrap();
+
+        database.init_for("/backup/to", Some("backup"), 1000, false, None).unwrap();
+        let entry2 = &Entry::new_simple(
+            "to/file1", "/backup/to/file1", "file1", "/backup/to", "00deadbeef", 100, 100, 100
+        );
+        database.add_entry(entry2).unwrap();
+
+        let roots: Vec<String> = database.get_roots(None).unwrap().into_iter().map(|metadata| metadata.name).collect();
+        assert_eq!(vec!["backup", "to"], roots);
+        assert_eq!(entry2.abspath, database.get_entry(&entry2.path).unwrap().abspath);
+        assert_eq!(0, database.find_dupes().unwrap().len(), "selected root only");
+
+        let mut database = Database::new(&connection);
+        assert_eq!(2, database.get_count(None).unwrap());
+        assert!(matches!(database.get_entry(&entry1.path), Err(DatabaseError::NoRootSelected)));
+        let dupe_files = database.find_dupes().unwrap();
+        let entries = &dupe_files[0].entries;
+        assert_eq!(entry2.abspath, entries[0].abspath);
+        assert_eq!(entry1.abspath, entries[1].abspath);
+
+        assert!(matches!(database.select_root(None, None), Err(DatabaseError::AmbiguousRoot(_))));
+        assert!(matches!(database.select_root(None, Some("other")), Err(DatabaseError::RootNotFound(_))));
+        database.select_root(None, Some("to")).unwrap();
+        assert_eq!(1, database.get_count(None).unwrap());
+    }
+
+    #[test]
+    fn same_name_for_another_path() {
+        let connection = Connection::open(":memory:").unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for("/path/to", None, 1000, false, None).unwrap();
+
+        let result = database.select_root_for("/other/to", None);
+        assert!(matches!(result, Err(DatabaseError::RootPathMismatch(name, path)) if name == "to" && path == "/path/to"));
+        let result = database.select_root_for("/path/to", Some("other"));
+        assert!(matches!(result, Err(DatabaseError::RootPathMismatch(name, _)) if name == "to"));
+    }
+
+    #[test]
+    fn rebase_root() {
+        let connection = Connection::open(":memory:").unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for("/mnt/backup", None, 1000, false, None).unwrap();
+        database.add_entry(&Entry::new_simple(
+            "file1", "/mnt/backup/file1", "file1", "/mnt/backup", "00deadbeef", 100, 100, 100
+        )).unwrap();
+        database.add_entry(&Entry::new_simple(
+            "to/file2", "/mnt/backup/to/file2", "file2", "/mnt/backup/to", "00cafecafe", 100, 100, 100
+        )).unwrap();
+        database.add_entry(&Entry::new_simple(
+            "file3", "/mnt/backup/file3", "file3", "/mnt/backup", "00c0ffee", 100, 100, 100
+        )).unwrap();
+        database.remove_entry("file3", 2000).unwrap();
+
+        assert_eq!(2, database.rebase_root("/media/backup").unwrap());
+        assert_eq!("/media/backup", database.get_metadata(None).unwrap().path);
+        let entry1 = database.get_entry("file1").unwrap();
+        assert_eq!("/media/backup/file1", entry1.abspath);
+        assert_eq!("/media/backup", entry1.dirname);
+        let entry2 = database.get_entry("to/file2").unwrap();
+        assert_eq!("/media/backup/to/file2", entry2.abspath);
+        assert_eq!("/media/backup/to", entry2.dirname);
+        assert_eq!("/media/backup/file3", database.get_deleted_entries().unwrap()[0].abspath);
+    }
+
+    #[test]
+    fn upgrade_single_root_database() {
+        let connection = Connection::open(":memory:").unwrap();
+        connection.execute_batch(
+            "CREATE TABLE metadata (path TEXT PRIMARY KEY, last_updated INTEGER);
+            INSERT INTO metadata VALUES ('/path/to', 1000);
+            CREATE TABLE entries (path TEXT PRIMARY KEY, abspath TEXT NOT NULL, basename TEXT NOT NULL,
+                dirname TEXT NOT NULL, signature TEXT NOT NULL, size INTEGER NOT NULL, timestamp INTEGER NOT NULL,
+                updated INTEGER NOT NULL);
+            INSERT INTO entries VALUES ('to/file1', '/path/to/file1', 'file1', '/path/to', '00deadbeef', 100, 100, 100);"
+        ).unwrap();
+
+        let mut database = Database::new(&connection);
+        database.init_for("/path/to", None, 2000, false, None).unwrap();
+        let entry = database.get_entry("to/file1").unwrap();
+        assert_eq!("00deadbeef", entry.signature);
+        assert_eq!("to", database.get_metadata(None).unwrap().name);
+    }
+}
+
+#[cfg(test)]
+mod deleted_tests {
+    use rusqlite::Connection;
+    use crate::Database;
+    use crate::model::model::Entry;
+
+    #[test]
+    fn deleted_entries() {
+        let connection = Connection::open(":memory:").unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for("/path/to", None, 1000, false, None).unwrap();
+        database.add_entry(&Entry::new_simple(
+            "file1", "/path/to/file1", "file1", "/path/to", "00deadbeef", 100, 100, 100
+        )).unwrap();
+        database.add_entry(&Entry::new_simple(
+            "file2", "/path/to/file2", "file2", "/path/to", "00cafecafe", 100, 100, 100
+        )).unwrap();
+
+        database.remove_entry("file1", 2000).unwrap();
+        database.remove_entry("file2", 3000).unwrap();
+        assert_eq!(0, database.get_count(None).unwrap());
+        let deleted = database.get_deleted_entries().unwrap();
+        assert_eq!(2, deleted.len());
+        assert_eq!("file2", deleted[0].path);
+        assert_eq!("00deadbeef", deleted[1].signature);
+        assert_eq!(2000, deleted[1].deleted);
+
+        assert_eq!(1, database.prune_deleted_entries(2500).unwrap());
+        assert_eq!(1, database.get_deleted_entries().unwrap().len());
+        // deleted in the same second.
+        assert_eq!(1, database.prune_deleted_entries(3000).unwrap());
+        assert!(database.get_deleted_entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn paths_under_directory() {
+        let connection = Connection::open(":memory:").unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for("/path/to", None, 1000, false, None).unwrap();
+        for path in ["dir", "dir/file1", "dir/sub/file2", "dir2/file3"] {
+            database.add_entry(&Entry::new_simple(
+                path, &format!("/path/to/{}", path), "file", "/path/to", "00deadbeef", 100, 100, 100
+            )).unwrap();
+        }
+
+        let mut paths = database.select_paths_under("dir").unwrap();
+        paths.sort();
+        assert_eq!(vec!["dir", "dir/file1", "dir/sub/file2"], paths);
+        assert_eq!(vec!["dir2/file3"], database.select_paths_under("dir2/file3").unwrap());
+    }
+}
+
+#[cfg(test)]
+mod move_tests {
+    use rusqlite::Connection;
+    use crate::Database;
+    use crate::model::model::{Block, Entry, MoveMethod};
+
+    #[test]
+    fn rename_entry() {
+        let connection = Connection::open(":memory:").unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for("/path/to", None, 1000, false, None).unwrap();
+        database.add_entry(&Entry::new_simple(
+            "file1", "/path/to/file1", "file1", "/path/to", "00deadbeef", 100, 100, 100
+        )).unwrap();
+        database.set_blocks("file1", &[Block { offset: 0, length: 100, signature: "00".to_string() }]).unwrap();
+
+        let moved = Entry::new_simple(
+            "sub/file2", "/path/to/sub/file2", "file2", "/path/to/sub", "00deadbeef", 100, 100, 100
+        );
+        database.rename_entry("file1", &moved).unwrap();
+        database.add_move(1, "file1", "sub/file2", MoveMethod::Inode, 1000).unwrap();
+
+        assert!(database.get_entry("file1").is_err());
+        assert_eq!("/path/to/sub/file2", database.get_entry("sub/file2").unwrap().abspath);
+        assert!(database.has_blocks("sub/file2").unwrap());
+        assert!(database.get_deleted_entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn added_copies() {
+        let connection = Connection::open(":memory:").unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for("/path/to", None, 1000, false, None).unwrap();
+        let vanished = &Entry::new_simple(
+            "file1", "/path/to/file1", "file1", "/path/to", "00deadbeef", 100, 100, 100
+        );
+        database.add_entry(vanished).unwrap();
+        database.add_entry(&Entry::new_simple(
+            "sub/file1", "/path/to/sub/file1", "file1", "/path/to/sub", "00deadbeef", 100, 100, 200
+        )).unwrap();
+        database.add_entry(&Entry::new_simple(
+            "sub/file2", "/path/to/sub/file2", "file2", "/path/to/sub", "00deadbeef", 100, 150, 200
+        )).unwrap();
+
+        let copies = database.find_added_copies(vanished, 200).unwrap();
+        assert_eq!(1, copies.len());
+        assert_eq!("sub/file1", copies[0].path);
+
+        database.add_move(1, "file1", "sub/file1", MoveMethod::Signature, 200).unwrap();
+        assert!(database.get_entry("file1").is_err());
+        assert!(database.find_added_copies(vanished, 200).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,145 @@
+// Copyright (c) 2022-2025 Chris Kroells
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+pub mod filter {
+    use std::path::{Path, PathBuf};
+    use globset::{Glob, GlobSet, GlobSetBuilder};
+    use ignore::gitignore::Gitignore;
+    use ignore::Match;
+    use log::warn;
+
+    /// Name of the per-directory file listing paths to ignore, using gitignore syntax.
+    pub const IGNORE_FILE_NAME: &str = ".mitsubachiignore";
+
+    /// Decides which paths under the root directory are indexed.
+    ///
+    /// Patterns are matched against both the path relative to the root and the file name, so
+    /// that a pattern like `node_modules` applies at any depth.
+    pub struct PathFilter {
+        root: PathBuf,
+        includes: Option<GlobSet>,
+        excludes: GlobSet,
+    }
+
+    impl PathFilter {
+        pub fn new(root: &Path, includes: &[String], excludes: &[String]) -> Result<PathFilter, globset::Error> {
+            let includes = match includes.is_empty() {
+                true => None,
+                false => Some(build_glob_set(includes)?),
+            };
+            Ok(PathFilter {
+                root: root.to_path_buf(),
+                includes,
+                excludes: build_glob_set(excludes)?,
+            })
+        }
+
+        /// Checks whether the path should be skipped.
+        ///
+        /// Include patterns only apply to files, so that directories are always descended into.
+        /// The ignore files are expected in order from the root towards the path, with the
+        /// deepest one taking precedence. The ignore files themselves are always skipped.
+        pub fn is_excluded(&self, path: &Path, is_dir: bool, ignore_files: &[Gitignore]) -> bool {
+            let relative_path = path.strip_prefix(&self.root).unwrap_or(path);
+            if !is_dir && path.file_name().is_some_and(|name| name == IGNORE_FILE_NAME) {
+                return true;
+            }
+            if matches_path(&self.excludes, relative_path) {
+                return true;
+            }
+            if !is_dir && self.includes.as_ref().is_some_and(|includes| !matches_path(includes, relative_path)) {
+                return true;
+            }
+
+            for ignore_file in ignore_files.iter().rev() {
+                match ignore_file.matched(path, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => continue,
+                }
+            }
+            false
+        }
+    }
+
+    /// Loads the ignore file in the specified directory, if any.
+    pub fn load_ignore_file(dir: &Path) -> Option<Gitignore> {
+        let ignore_path = dir.join(IGNORE_FILE_NAME);
+        if !ignore_path.is_file() {
+            return None;
+        }
+
+        let (ignore_file, error) = Gitignore::new(&ignore_path);
+        if let Some(why) = error {
+            warn!("Error while reading {:?}, some patterns may be ignored. -> {}", ignore_path, why);
+        }
+        Some(ignore_file)
+    }
+
+    fn build_glob_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            builder.add(Glob::new(pattern)?);
+        }
+        builder.build()
+    }
+
+    fn matches_path(glob_set: &GlobSet, relative_path: &Path) -> bool {
+        glob_set.is_match(relative_path)
+            || relative_path.file_name().is_some_and(|name| glob_set.is_match(name))
+    }
+}
+
+#[cfg(test)]
+mod filter_tests {
+    use std::path::Path;
+    use crate::filter::filter::PathFilter;
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn excludes_by_name_at_any_depth() {
+        let filter = PathFilter::new(Path::new("/root"), &[], &patterns(&["node_modules", "*.swp"])).unwrap();
+
+        assert!(filter.is_excluded(Path::new("/root/node_modules"), true, &[]));
+        assert!(filter.is_excluded(Path::new("/root/a/b/node_modules"), true, &[]));
+        assert!(filter.is_excluded(Path::new("/root/a/.file.swp"), false, &[]));
+        assert!(!filter.is_excluded(Path::new("/root/a/file.txt"), false, &[]));
+    }
+
+    #[test]
+    fn excludes_by_relative_path() {
+        let filter = PathFilter::new(Path::new("/root"), &[], &patterns(&["photos/raw"])).unwrap();
+
+        assert!(filter.is_excluded(Path::new("/root/photos/raw"), true, &[]));
+        assert!(!filter.is_excluded(Path::new("/root/other/raw"), true, &[]));
+    }
+
+    #[test]
+    fn includes_only_apply_to_files() {
+        let filter = PathFilter::new(Path::new("/root"), &patterns(&["*.jpg"]), &[]).unwrap();
+
+        assert!(!filter.is_excluded(Path::new("/root/photos"), true, &[]));
+        assert!(!filter.is_excluded(Path::new("/root/photos/a.jpg"), false, &[]));
+        assert!(filter.is_excluded(Path::new("/root/photos/a.txt"), false, &[]));
+    }
+}
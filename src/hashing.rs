@@ -0,0 +1,485 @@
+// Copyright (c) 2022-2025 Chris Kroells
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+pub mod hashing {
+    use std::{fmt, fs, io};
+    use std::fmt::Formatter;
+    use std::io::{Error, ErrorKind, Write};
+    use std::os::fd::AsRawFd;
+    use std::ops::Range;
+    use std::os::unix::fs::{FileExt, OpenOptionsExt};
+    use std::path::Path;
+    use std::str::FromStr;
+    use clap::ValueEnum;
+    use log::{debug, error, warn};
+    use sha2::{Digest, Sha256, Sha512};
+    use xxhash_rust::xxh3::Xxh3;
+
+    use crate::model::model::Block;
+
+    /// Algorithms which can be used to compute the signature of a file.
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+    pub enum HashAlgorithm {
+        #[default]
+        Sha256,
+        Sha512,
+        Blake3,
+        /// 128-bit variant of XXH3. Not a cryptographic hash.
+        Xxh3,
+    }
+
+    impl HashAlgorithm {
+        pub const ALL: [HashAlgorithm; 4] = [
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Sha512,
+            HashAlgorithm::Blake3,
+            HashAlgorithm::Xxh3,
+        ];
+
+        /// Name of the algorithm as stored in the database.
+        pub fn name(&self) -> &'static str {
+            match self {
+                HashAlgorithm::Sha256 => "sha256",
+                HashAlgorithm::Sha512 => "sha512",
+                HashAlgorithm::Blake3 => "blake3",
+                HashAlgorithm::Xxh3 => "xxh3",
+            }
+        }
+
+        pub fn hasher(&self) -> Hasher {
+            match self {
+                HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+                HashAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+                HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+                HashAlgorithm::Xxh3 => Hasher::Xxh3(Box::new(Xxh3::new())),
+            }
+        }
+    }
+
+    impl fmt::Display for HashAlgorithm {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.name())
+        }
+    }
+
+    impl FromStr for HashAlgorithm {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            HashAlgorithm::ALL.into_iter()
+                .find(|algorithm| algorithm.name() == s)
+                .ok_or(format!("Unknown hash algorithm: {}", s))
+        }
+    }
+
+    /// Incremental hasher for any of the supported algorithms.
+    pub enum Hasher {
+        Sha256(Sha256),
+        Sha512(Sha512),
+        Blake3(Box<blake3::Hasher>),
+        Xxh3(Box<Xxh3>),
+    }
+
+    impl Hasher {
+        pub fn update(&mut self, data: &[u8]) {
+            match self {
+                Hasher::Sha256(hasher) => hasher.update(data),
+                Hasher::Sha512(hasher) => hasher.update(data),
+                Hasher::Blake3(hasher) => { hasher.update(data); },
+                Hasher::Xxh3(hasher) => hasher.update(data),
+            }
+        }
+
+        /// Returns the hash as a lowercase hex string.
+        pub fn finalize(self) -> String {
+            let hash = match self {
+                Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+                Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+                Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+                Hasher::Xxh3(hasher) => hasher.digest128().to_be_bytes().to_vec(),
+            };
+
+            let mut hex_hash = vec![0u8; hash.len() * 2];
+            match base16ct::lower::encode_str(&hash, &mut hex_hash) {
+                Err(why) => {
+                    error!("Error occurred during stringifying the hash. Caused by {}", why);
+                    panic!("Error occurred during stringifying the hash. Caused by {}", why);
+                },
+                Ok(res) => res.to_string()
+            }
+        }
+    }
+
+    impl Write for Hasher {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.update(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Hashes the contents of a file as a whole, and optionally in blocks of a fixed size.
+    struct FileHasher {
+        algorithm: HashAlgorithm,
+        hasher: Hasher,
+        block_size: Option<u64>,
+        block_hasher: Hasher,
+        /// Number of bytes hashed into the current block.
+        block_length: u64,
+        blocks: Vec<Block>,
+    }
+
+    impl FileHasher {
+        fn new(algorithm: HashAlgorithm, block_size: Option<u64>) -> FileHasher {
+            FileHasher {
+                algorithm,
+                hasher: algorithm.hasher(),
+                block_size,
+                block_hasher: algorithm.hasher(),
+                block_length: 0,
+                blocks: Vec::new(),
+            }
+        }
+
+        fn update(&mut self, data: &[u8]) {
+            self.hasher.update(data);
+            let block_size = match self.block_size {
+                Some(any) => any,
+                None => return,
+            };
+
+            let mut remaining = data;
+            while !remaining.is_empty() {
+                let n = remaining.len().min((block_size - self.block_length) as usize);
+                self.block_hasher.update(&remaining[.. n]);
+                self.block_length += n as u64;
+                remaining = &remaining[n ..];
+                if self.block_length == block_size {
+                    self.finish_block();
+                }
+            }
+        }
+
+        fn finish_block(&mut self) {
+            let offset = self.blocks.last().map_or(0, |block| block.offset + block.length);
+            let block_hasher = std::mem::replace(&mut self.block_hasher, self.algorithm.hasher());
+            self.blocks.push(Block { offset, length: self.block_length, signature: block_hasher.finalize() });
+            self.block_length = 0;
+        }
+
+        fn finalize(mut self) -> (String, Vec<Block>) {
+            if self.block_length > 0 {
+                self.finish_block();
+            }
+            (self.hasher.finalize(), self.blocks)
+        }
+    }
+
+    /// Signature of the contents of a file, along with how it was read.
+    #[derive(Debug)]
+    pub struct FileSignature {
+        pub signature: String,
+        /// Signatures of consecutive blocks of the file, when hashed with a block size.
+        pub blocks: Vec<Block>,
+        /// Read mode actually used, which differs from the requested one when `O_DIRECT` is not
+        /// supported for the file.
+        pub read_mode: ReadMode,
+    }
+
+    /// How files are read when hashing.
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+    pub enum ReadMode {
+        /// Read through the page cache, which may return data that never reached the disk.
+        #[default]
+        Cached,
+        /// Bypass the page cache with `O_DIRECT`, falling back to `fadvise` when unsupported.
+        Direct,
+        /// Evict the file from the page cache with `posix_fadvise` before and after reading.
+        /// Pages which have not been written to the disk yet cannot be evicted.
+        Fadvise,
+    }
+
+    impl ReadMode {
+        /// Name of the read mode as stored in the database.
+        pub fn name(&self) -> &'static str {
+            match self {
+                ReadMode::Cached => "cached",
+                ReadMode::Direct => "direct",
+                ReadMode::Fadvise => "fadvise",
+            }
+        }
+    }
+
+    impl fmt::Display for ReadMode {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.name())
+        }
+    }
+
+    /// Settings for hashing files.
+    #[derive(Debug, Clone, Copy)]
+    pub struct HashOptions {
+        pub algorithm: HashAlgorithm,
+        pub read_mode: ReadMode,
+        /// Size of blocks to also hash the file in.
+        pub block_size: Option<u64>,
+        /// Number of times to retry a failed read before skipping past the unreadable region.
+        /// When `None`, hashing stops at the first failed read.
+        pub retries: Option<u32>,
+    }
+
+    /// Error reading a file, along with where in the file it occurred.
+    #[derive(Debug)]
+    pub struct ReadError {
+        pub error: Error,
+        /// Byte offset of the (first) failed read, or `None` when the file could not be opened.
+        pub offset: Option<u64>,
+        /// Byte ranges which could not be read, when reading skipped past unreadable regions.
+        pub bad_ranges: Vec<Range<u64>>,
+    }
+
+    impl From<Error> for ReadError {
+        fn from(error: Error) -> Self {
+            ReadError { error, offset: None, bad_ranges: Vec::new() }
+        }
+    }
+
+    impl fmt::Display for ReadError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            match self.offset {
+                Some(offset) => write!(f, "{} (at byte {})", self.error, offset)?,
+                None => write!(f, "{}", self.error)?,
+            }
+            if !self.bad_ranges.is_empty() {
+                write!(f, ", unreadable byte ranges: {:?}", self.bad_ranges)?;
+            }
+            Ok(())
+        }
+    }
+
+    impl std::error::Error for ReadError {}
+
+    /// Buffer size for reads, which must be a multiple of the logical block size for `O_DIRECT`.
+    const BUFFER_SIZE: usize = 1024 * 1024;
+    /// Alignment of the buffer for `O_DIRECT` reads.
+    const DIRECT_ALIGNMENT: usize = 4096;
+    /// Size of the reads used to skip past an unreadable region, aligned for `O_DIRECT`.
+    const RECOVERY_STEP: u64 = 4096;
+
+    /// Hashes the contents of the file, and also every block of it when a block size is given.
+    ///
+    /// When retries are enabled, reading continues past regions which cannot be read, so that all
+    /// of them are found. The file is still reported as failed, with the unreadable byte ranges.
+    pub fn hash_file(path: &Path, options: &HashOptions) -> Result<FileSignature, ReadError> {
+        let mut hasher = FileHasher::new(options.algorithm, options.block_size);
+        let read_mode = match options.read_mode {
+            ReadMode::Cached => {
+                let file = fs::File::open(path)?;
+                read_into(&file, &mut vec![0u8; BUFFER_SIZE], &mut hasher, options.retries)?;
+                ReadMode::Cached
+            },
+            ReadMode::Direct => match read_direct(path, &mut hasher, options.retries) {
+                Ok(()) => ReadMode::Direct,
+                Err(why) if why.error.raw_os_error() == Some(libc::EINVAL) && why.bad_ranges.is_empty() => {
+                    debug!("O_DIRECT is not supported, falling back to fadvise -> {:?}", path);
+                    hasher = FileHasher::new(options.algorithm, options.block_size);
+                    read_fadvise(path, &mut hasher, options.retries)?;
+                    ReadMode::Fadvise
+                },
+                Err(why) => return Err(why),
+            },
+            ReadMode::Fadvise => {
+                read_fadvise(path, &mut hasher, options.retries)?;
+                ReadMode::Fadvise
+            },
+        };
+
+        let (signature, blocks) = hasher.finalize();
+        Ok(FileSignature { signature, blocks, read_mode })
+    }
+
+    fn read_direct(path: &Path, hasher: &mut FileHasher, retries: Option<u32>) -> Result<(), ReadError> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)?;
+
+        let mut raw_buffer = vec![0u8; BUFFER_SIZE + DIRECT_ALIGNMENT];
+        let offset = raw_buffer.as_ptr().align_offset(DIRECT_ALIGNMENT);
+        read_into(&file, &mut raw_buffer[offset .. offset + BUFFER_SIZE], hasher, retries)
+    }
+
+    fn read_fadvise(path: &Path, hasher: &mut FileHasher, retries: Option<u32>) -> Result<(), ReadError> {
+        let file = fs::File::open(path)?;
+        drop_from_page_cache(&file);
+        let result = read_into(&file, &mut vec![0u8; BUFFER_SIZE], hasher, retries);
+        drop_from_page_cache(&file);
+        result
+    }
+
+    /// Reads the file until the end, hashing everything read.
+    ///
+    /// With retries, a buffer which keeps failing to be read is read again in small steps, and
+    /// the steps which fail are skipped. The skipped byte ranges are returned in the error once
+    /// the end of the file is reached.
+    fn read_into(file: &fs::File, buffer: &mut [u8], hasher: &mut FileHasher, retries: Option<u32>) -> Result<(), ReadError> {
+        let len = file.metadata()?.len();
+        let mut offset: u64 = 0;
+        // end of the buffer which failed to be read, which is read in small steps.
+        let mut small_reads_until: u64 = 0;
+        let mut first_error: Option<Error> = None;
+        let mut bad_ranges: Vec<Range<u64>> = Vec::new();
+        loop {
+            let is_small_read = offset < small_reads_until;
+            let length = match is_small_read {
+                true => RECOVERY_STEP as usize,
+                false => buffer.len(),
+            };
+            match read_at(file, &mut buffer[.. length], offset, retries.unwrap_or(0)) {
+                Ok(0) => break,
+                Ok(n) => {
+                    hasher.update(&buffer[.. n]);
+                    offset += n as u64;
+                    // with O_DIRECT, reading on from the unaligned offset after the end would fail.
+                    if n < length && offset >= len {
+                        break;
+                    }
+                },
+                Err(why) if retries.is_none() => {
+                    return Err(ReadError { error: why, offset: Some(offset), bad_ranges })
+                },
+                Err(why) if !is_small_read => {
+                    debug!("Reading bytes from {} in small steps -> {}", offset, why);
+                    small_reads_until = offset + buffer.len() as u64;
+                },
+                Err(why) => {
+                    // the last step ends with the file, rather than on a multiple of the step.
+                    let end = ((offset / RECOVERY_STEP + 1) * RECOVERY_STEP).min(len.max(offset + 1));
+                    warn!("Skipping unreadable bytes {}..{} -> {}", offset, end, why);
+                    match bad_ranges.last_mut() {
+                        Some(range) if range.end == offset => range.end = end,
+                        _ => bad_ranges.push(offset .. end),
+                    }
+                    first_error.get_or_insert(why);
+                    offset = end;
+                    if offset >= len {
+                        break;
+                    }
+                },
+            }
+        }
+
+        match first_error {
+            Some(error) => Err(ReadError { error, offset: bad_ranges.first().map(|range| range.start), bad_ranges }),
+            None => Ok(()),
+        }
+    }
+
+    /// Reads at the offset, retrying a failed read up to the given number of times.
+    fn read_at(file: &fs::File, buffer: &mut [u8], offset: u64, retries: u32) -> Result<usize, Error> {
+        let mut attempt = 0;
+        loop {
+            match file.read_at(buffer, offset) {
+                Ok(n) => return Ok(n),
+                Err(why) if why.kind() == ErrorKind::Interrupted => continue,
+                Err(why) if attempt < retries => {
+                    attempt += 1;
+                    debug!("Retrying failed read at byte {} ({}/{}) -> {}", offset, attempt, retries, why);
+                },
+                Err(why) => return Err(why),
+            }
+        }
+    }
+
+    fn drop_from_page_cache(file: &fs::File) {
+        let result = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+        if result != 0 {
+            warn!("Could not drop file from page cache -> {}", Error::from_raw_os_error(result));
+        }
+    }
+}
+
+#[cfg(test)]
+mod hashing_tests {
+    use std::fs;
+    use crate::hashing::hashing::{hash_file, HashAlgorithm, HashOptions, ReadMode};
+    use crate::testing::testing::TempDir;
+
+    #[test]
+    fn known_signatures() {
+        let expected: [(HashAlgorithm, &[u8], &str); 7] = [
+            (HashAlgorithm::Sha256, b"hello", "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"),
+            (HashAlgorithm::Sha512, b"hello", "9b71d224bd62f3785d96d46ad3ea3d73319bfbc2890caadae2dff72519673ca72323c3d99ba5c11d7c7acc6e14b8c5da0c4663475c2e5c3adef46f73bcdec043"),
+            (HashAlgorithm::Sha512, b"", "cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e"),
+            (HashAlgorithm::Blake3, b"hello", "ea8f163db38682925e4491c5e58d4bb3506ef8c14eb78a86e908c5624a67200f"),
+            (HashAlgorithm::Blake3, b"", "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"),
+            (HashAlgorithm::Xxh3, b"hello", "b5e9c1ad071b3e7fc779cfaa5e523818"),
+            (HashAlgorithm::Xxh3, b"", "99aa06d3014798d86001c324468d497f"),
+        ];
+        for (algorithm, input, signature) in expected {
+            let mut hasher = algorithm.hasher();
+            hasher.update(input);
+            assert_eq!(signature, hasher.finalize(), "{} of {:?}", algorithm, input);
+        }
+    }
+
+    #[test]
+    fn name_round_trip() {
+        for algorithm in HashAlgorithm::ALL {
+            assert_eq!(algorithm, algorithm.name().parse().unwrap());
+        }
+    }
+
+    #[test]
+    fn block_signatures() {
+        let dir = TempDir::new("blocks");
+        let path = dir.join("file");
+        fs::write(&path, b"hellohelloxy").unwrap();
+        let options = HashOptions { algorithm: HashAlgorithm::Sha256, read_mode: ReadMode::Cached, block_size: Some(5), retries: None };
+        let hashed = hash_file(&path, &options).unwrap();
+        let hello = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let offsets: Vec<(u64, u64)> = hashed.blocks.iter().map(|block| (block.offset, block.length)).collect();
+        assert_eq!(vec![(0, 5), (5, 5), (10, 2)], offsets);
+        assert_eq!(hello, hashed.blocks[0].signature);
+        assert_eq!(hello, hashed.blocks[1].signature);
+        assert_ne!(hello, hashed.signature);
+    }
+
+    #[test]
+    fn read_modes_agree() {
+        let dir = TempDir::new("read-modes");
+        let path = dir.join("file");
+        // not a multiple of the O_DIRECT alignment, so that the last read is short.
+        let contents: Vec<u8> = (0 .. 3 * 4096 + 100).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &contents).unwrap();
+        let hashed = [ReadMode::Cached, ReadMode::Direct, ReadMode::Fadvise].map(|read_mode| {
+            let options = HashOptions { algorithm: HashAlgorithm::Sha256, read_mode, block_size: Some(4096), retries: Some(1) };
+            hash_file(&path, &options).unwrap()
+        });
+        let [cached, direct, fadvise] = hashed;
+        assert_eq!(cached.signature, direct.signature);
+        assert_eq!(cached.signature, fadvise.signature);
+        assert_eq!(cached.blocks, direct.blocks);
+        assert_eq!(4, cached.blocks.len());
+    }
+}
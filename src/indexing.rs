@@ -19,95 +19,59 @@
 // THE SOFTWARE.
 
 pub mod indexing {
-use std::{fmt, fs, io};
-    use std::collections::HashSet;
+    use std::{fmt, fs};
+    use std::cell::{Cell, RefCell};
+    use std::collections::{HashMap, HashSet};
     use std::fmt::Formatter;
-    use std::fs::DirEntry;
-    use std::io::Error;
-    use std::ops::Add;
+    use std::fs::{DirEntry, Metadata};
+    use std::io::{Error, ErrorKind};
     use std::path::{Path, PathBuf};
-    use std::sync::{Arc, Mutex};
+    use std::os::unix::fs::MetadataExt;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::sync::mpsc::{Receiver, Sender, SyncSender};
     use std::sync::atomic::{AtomicU64, Ordering};
-    use std::time::{Duration, SystemTime, UNIX_EPOCH};
-    use log::{debug, error, info, warn};
-    use rusqlite::Connection;
-    use sha2::{Digest, Sha256};
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use log::{debug, error, info, log, warn, Level};
+    use rusqlite::{Connection, OpenFlags};
 
     use crate::db::db::{Database, DatabaseError};
-    use crate::model::model::{abspath_to_path, Entry, path_to_string};
-
-    fn traverse(dir: &Path, callback: &dyn Fn(&DirEntry) -> (), options: Option<&IndexingOptions>) -> Result<(), IndexingError> {
-        let terminate_at: Option<SystemTime> = match options.is_some() {
-            true => match options.unwrap().duration.is_some() {
-                true => Some(SystemTime::now().add(Duration::from_secs(options.unwrap().duration.unwrap()))),
-                false => None
-            },
-            false => None
-        };
-
-        if dir.is_dir() {
-            let entries = match fs::read_dir(dir) {
-                Ok(any) => any,
-                Err(err) => {
-                    error!("Error while attempting to read entries in {:?}! -> {}", dir, err);
-                    return Err(
-                        IndexingError::ExecutionError(
-                            err, format!("Error while attempting to read entries in {:?}!", dir)
-                        )
-                    );
-                }
-            };
-            for entry in entries {
-                if entry.is_err() {
-                    error!("Error! -> {}", entry.err().unwrap());
-                    continue;
-                }
-
-                if terminate_at.is_some() && SystemTime::now() > terminate_at.unwrap() {
-                    info!("Execution timed out.");
-                    return Err(IndexingError::ExecutionTimeout);
-                }
-
-                let entry = entry.unwrap();
-                let path = entry.path();
-                if path.is_dir() {
-                    return traverse(&path, callback, options);
-                } else if path.is_file() {
-                    callback(&entry)
-                } else if path.is_symlink() {
-                    // skip symlinks?
-                } else {
-                    // skip any other types?
-                }
-            }
-        }
-        Ok(())
-    }
+    use crate::filter::filter::PathFilter;
+    use crate::hashing::hashing::{hash_file, FileSignature, HashAlgorithm, HashOptions, ReadError, ReadMode};
+    use crate::model::model::{abspath_to_path, Block, Entry, MoveMethod, path_to_string};
+    use crate::progress::progress::{format_bytes, Progress};
+    use crate::traversal::traversal::{traverse, SymlinkPolicy, TraversalOptions};
 
-    fn verify_root_path(path: &Path) -> &Path {
+    fn verify_root_path(path: &Path) -> Result<&Path, IndexingError> {
         let path_str = path.to_str().unwrap();
         if !path.exists() {
             error!("Specified root directory does not exist: {}", path_str);
-            panic!("Specified root directory does not exist: {}", path_str);
+            return Err(IndexingError::ExecutionError(
+                Error::new(ErrorKind::NotFound, path_str), "Specified root directory does not exist".to_string()
+            ));
         } else if !path.is_dir() {
-            error!("Specified root directory does not exist: {}", path_str);
-            panic!("Specified root directory is not a directory: {}", path_str);
+            error!("Specified root directory is not a directory: {}", path_str);
+            return Err(IndexingError::ExecutionError(
+                Error::new(ErrorKind::InvalidInput, path_str), "Specified root directory is not a directory".to_string()
+            ));
         }
-        path
+        Ok(path)
     }
 
-    /// Find indexed files that no longer exist.
-    fn remove_deleted_files(db: &Database, root_dir: &Path) -> Result<usize, rusqlite::Error> {
+    /// Find indexed files that no longer exist, or are now excluded by the filter.
+    fn find_deleted_files(db: &Database, root_dir: &Path, traversal_options: &TraversalOptions) -> Result<Vec<String>, DatabaseError> {
         let paths = db.select_all_paths()?;
         let paths_in_db: HashSet<String> = HashSet::from_iter(paths);
 
         let paths_on_disk: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
-        let callback: &dyn Fn(&DirEntry) -> () = &|dir_entry| {
+        let callback: &dyn Fn(&DirEntry) = &|dir_entry| {
             let path_buf = dir_entry.path();
             let path = path_to_string(&path_buf);
             paths_on_disk.lock().unwrap().insert(path);
         };
-        traverse(root_dir, callback, None);
+        if let Err(any) = traverse(root_dir, callback, traversal_options, None, None) {
+            warn!("Error occurred while looking for deleted files. caused by: {}", any);
+        }
 
         let _x = paths_on_disk.lock().unwrap().to_owned();
         let difference = paths_in_db.difference(&_x);
@@ -117,27 +81,144 @@ use std::{fmt, fs, io};
             abspath_to_path(root_dir, Path::new(x))
         }));
         info!("difference as paths: {:?}", difference_as_paths);
+        Ok(difference_as_paths)
+    }
+
+    /// Removes entries of files which no longer exist, or are now excluded by the filter.
+    fn remove_deleted_files(db: &Database, root_dir: &Path, traversal_options: &TraversalOptions, run_id: i64, now_timestamp: u64) -> Result<(usize, usize), DatabaseError> {
+        remove_entries(db, &find_deleted_files(db, root_dir, traversal_options)?, run_id, now_timestamp)
+    }
 
+    /// Removes the entries, unless a file with the same contents and modification time was added
+    /// by the run, which is recorded as a move instead.
+    ///
+    /// Returns the number of removed and moved files.
+    fn remove_entries(db: &Database, keys: &[String], run_id: i64, now_timestamp: u64) -> Result<(usize, usize), DatabaseError> {
         let mut delete_count = 0;
-        for path in difference_as_paths {
-            debug!("Removing entry with key -> {}", path);
-            db.remove_entry(&path).unwrap();
-            delete_count += 1;
+        let mut move_count = 0;
+        for path in keys {
+            let entry = db.get_entry(path)?;
+            match db.find_added_copies(&entry, now_timestamp)?.first() {
+                Some(moved) => {
+                    info!("Moved -> {} to {}", path, moved.path);
+                    db.add_move(run_id, path, &moved.path, MoveMethod::Signature, now_timestamp)?;
+                    move_count += 1;
+                },
+                None => {
+                    debug!("Removing entry with key -> {}", path);
+                    db.remove_entry(path, now_timestamp)?;
+                    delete_count += 1;
+                },
+            }
         }
 
-        Ok(delete_count)
+        Ok((delete_count, move_count))
     }
 
+    /// Default number of files to write to the database in one transaction.
+    pub const BATCH_SIZE: u64 = 1000;
+    /// Default number of seconds after which the files written so far are committed.
+    pub const BATCH_INTERVAL: u64 = 5;
+
     pub struct IndexingOptions {
+        /// Name of the root in the database. Defaults to the name of an existing root for the
+        /// directory, or else its last component.
+        pub name: Option<String>,
         pub skip_delete_check: bool,
+        /// Number of days to keep records of files removed from the index. Kept forever when `None`.
+        pub keep_deleted: Option<u64>,
         pub duration: Option<u64>,
         pub no_sync: bool,
+        /// Number of files to hash concurrently.
+        pub jobs: usize,
+        /// Hash algorithm for a new database. Existing databases keep their algorithm.
+        pub algorithm: Option<HashAlgorithm>,
+        /// Glob patterns of files to index. When empty, all files are indexed.
+        pub includes: Vec<String>,
+        /// Glob patterns of files and directories to skip.
+        pub excludes: Vec<String>,
+        pub symlinks: SymlinkPolicy,
+        /// Skip directories on a different file system than the root directory.
+        pub one_file_system: bool,
+        /// Continue from where the last unfinished run stopped.
+        pub resume: bool,
+        /// How files are read from storage while hashing.
+        pub read_mode: ReadMode,
+        /// Size of blocks to also hash files in, to locate changes within files later on.
+        /// Kept by the database for later runs, and 0 stops storing block signatures.
+        pub block_size: Option<u64>,
+        /// Number of times to retry failed reads, before skipping past unreadable regions and
+        /// recording them. When `None`, a file is given up on at the first failed read.
+        pub retries: Option<u32>,
+        /// Skip files modified or changed less than this many seconds ago, as they may still be
+        /// written to. Their entries are kept as they are until a later run.
+        pub min_age: Option<u64>,
+        /// Number of files written to the database in one transaction, so that a crash loses at
+        /// most this many files, which the next run picks up again.
+        pub batch_size: u64,
+        /// Number of seconds after which a transaction is committed, even if it holds fewer files.
+        pub batch_interval: u64,
+        /// Count the files to process before indexing, to show progress.
+        pub progress: bool,
+        /// Only report the files which would be added, re-hashed or removed, without hashing
+        /// anything or writing to the database.
+        pub dry_run: bool,
+    }
+
+    impl Default for IndexingOptions {
+        /// Indexes every file with one hashing worker, as the `index` command does without options.
+        fn default() -> Self {
+            IndexingOptions {
+                name: None,
+                skip_delete_check: false,
+                keep_deleted: None,
+                duration: None,
+                no_sync: false,
+                jobs: 1,
+                algorithm: None,
+                includes: Vec::new(),
+                excludes: Vec::new(),
+                symlinks: SymlinkPolicy::default(),
+                one_file_system: false,
+                resume: false,
+                read_mode: ReadMode::default(),
+                block_size: None,
+                retries: None,
+                min_age: None,
+                batch_size: BATCH_SIZE,
+                batch_interval: BATCH_INTERVAL,
+                progress: false,
+                dry_run: false,
+            }
+        }
     }
     
+    /// Numbers of files handled by an indexing run, or which a dry run would handle.
+    #[derive(Debug, Default)]
+    pub struct IndexingSummary {
+        pub added: u64,
+        pub updated: u64,
+        pub moved: u64,
+        /// Number of entries removed, or `None` when the check for deleted files was skipped.
+        pub deleted: Option<u64>,
+        pub skipped: u64,
+        /// Files left for a later run, as they were modified too recently or kept changing while
+        /// being read.
+        pub unstable: u64,
+        pub errors: u64,
+        /// Number of files read with `fadvise` because `O_DIRECT` was not supported.
+        pub fallback_reads: u64,
+        pub skipped_mount_points: Vec<PathBuf>,
+        /// Whether the whole root was traversed, rather than stopping once the duration passed.
+        pub completed: bool,
+    }
+
     #[derive(Debug)]
     pub enum IndexingError {
         ExecutionError(Error, String),
         ExecutionTimeout,
+        /// Reading or writing the database failed, stopping the run.
+        Database(DatabaseError),
     }
 
     impl fmt::Display for IndexingError {
@@ -145,133 +226,1222 @@ use std::{fmt, fs, io};
             match self {
                 IndexingError::ExecutionError(e, message) => write!(f, "Execution error: {} caused by: {}", message, e),
                 IndexingError::ExecutionTimeout => write!(f, "Execution timed out."),
+                IndexingError::Database(e) => write!(f, "{}", e),
             }
         }
     }
 
     impl std::error::Error for IndexingError {}
 
-    pub fn index(output_file: &Path, root_dir: &Path, options: &IndexingOptions) -> Result<(), Error> {
-        let root = verify_root_path(root_dir);
+    impl From<DatabaseError> for IndexingError {
+        fn from(error: DatabaseError) -> Self {
+            IndexingError::Database(error)
+        }
+    }
+
+    impl From<rusqlite::Error> for IndexingError {
+        fn from(error: rusqlite::Error) -> Self {
+            IndexingError::Database(DatabaseError::from(error))
+        }
+    }
+
+    /// A file which needs to be hashed, sent from the traversal to the hashing workers.
+    pub(crate) struct HashJob {
+        path_buf: PathBuf,
+        size: u64,
+        mod_timestamp: i64,
+        mod_timestamp_nsec: i64,
+        ctime: i64,
+        ctime_nsec: i64,
+        device: u64,
+        inode: u64,
+        /// Number of hard links to the file.
+        links: u64,
+        /// Device and inode of the file when the job was created, under which its other hard links
+        /// wait for the signature, even if the file is replaced while it is hashed.
+        link_key: (u64, u64),
+        is_update: bool,
+        /// Set when the file kept changing while it was hashed.
+        pub(crate) unstable: bool,
+    }
+
+    /// Number of times a file which changed while it was hashed is hashed again, before giving up on it.
+    const UNSTABLE_RETRIES: u32 = 2;
+
+    impl HashJob {
+        pub(crate) fn new(path_buf: PathBuf, metadata: &Metadata, is_update: bool) -> HashJob {
+            HashJob {
+                path_buf,
+                size: metadata.len(),
+                mod_timestamp: metadata.mtime(),
+                mod_timestamp_nsec: metadata.mtime_nsec(),
+                ctime: metadata.ctime(),
+                ctime_nsec: metadata.ctime_nsec(),
+                device: metadata.dev(),
+                inode: metadata.ino(),
+                links: metadata.nlink(),
+                link_key: (metadata.dev(), metadata.ino()),
+                is_update,
+                unstable: false,
+            }
+        }
+
+        /// Checks whether the file changed since the job was created, such as when it is replaced
+        /// by renaming another file over it.
+        fn has_changed(&self, metadata: &Metadata) -> bool {
+            metadata.len() != self.size
+                || metadata.mtime() != self.mod_timestamp
+                || metadata.mtime_nsec() != self.mod_timestamp_nsec
+                || metadata.ino() != self.inode
+        }
+
+        pub(crate) fn to_entry(&self, root: &Path, signature: &str, now_timestamp: u64) -> Entry {
+            Entry {
+                device: Some(self.device),
+                inode: Some(self.inode),
+                timestamp_nsec: Some(self.mod_timestamp_nsec),
+                ctime: Some(self.ctime),
+                ctime_nsec: Some(self.ctime_nsec),
+                ..Entry::new(&self.path_buf, root, signature, self.size, self.mod_timestamp, now_timestamp)
+            }
+        }
+    }
+
+    /// A hashed file, sent from the hashing workers back to the thread owning the database.
+    pub(crate) struct HashResult {
+        pub(crate) job: HashJob,
+        pub(crate) entry: Result<Entry, ReadError>,
+        pub(crate) blocks: Vec<Block>,
+        /// Read mode actually used, which differs from the requested one after a fallback.
+        pub(crate) read_mode: ReadMode,
+    }
+
+    #[derive(Default)]
+    pub(crate) struct IndexingCounts {
+        added: AtomicU64,
+        updated: AtomicU64,
+        moved: AtomicU64,
+        skipped: AtomicU64,
+        /// Files skipped as they were still being written to.
+        pub(crate) unstable: AtomicU64,
+        pub(crate) errors: AtomicU64,
+        fallback_reads: AtomicU64,
+    }
+
+    impl IndexingCounts {
+        fn into_summary(self, deleted: Option<u64>, skipped_mount_points: Vec<PathBuf>, completed: bool) -> IndexingSummary {
+            IndexingSummary {
+                added: self.added.into_inner(),
+                updated: self.updated.into_inner(),
+                moved: self.moved.into_inner(),
+                deleted,
+                skipped: self.skipped.into_inner(),
+                unstable: self.unstable.into_inner(),
+                errors: self.errors.into_inner(),
+                fallback_reads: self.fallback_reads.into_inner(),
+                skipped_mount_points,
+                completed,
+            }
+        }
+    }
+
+    /// Progress of hashing a file with several hard links during a run.
+    pub(crate) enum HardlinkState {
+        /// The first link is being hashed, and the other links wait for its signature.
+        Pending(Vec<HashJob>),
+        Hashed(String, Vec<Block>),
+    }
+
+    /// Writes to the database in transactions, committed every so many files or seconds.
+    pub(crate) struct Batch {
+        size: u64,
+        interval: Duration,
+        pub(crate) files: Cell<u64>,
+        pub(crate) started: Cell<Instant>,
+    }
+
+    impl Batch {
+        fn new(options: &IndexingOptions) -> Batch {
+            Batch {
+                size: options.batch_size.max(1),
+                interval: Duration::from_secs(options.batch_interval),
+                files: Cell::new(0),
+                started: Cell::new(Instant::now()),
+            }
+        }
+    }
+
+    /// Writes hashed files to the database, from the thread owning the database.
+    ///
+    /// Files with several hard links are only hashed once per run, with the other links reusing
+    /// the signature of the first one.
+    pub(crate) struct IndexWriter<'a> {
+        db: &'a Database<'a>,
+        root: &'a Path,
+        now_timestamp: u64,
+        run_id: i64,
+        read_mode: ReadMode,
+        pub(crate) counts: IndexingCounts,
+        pub(crate) hardlinks: RefCell<HashMap<(u64, u64), HardlinkState>>,
+        progress: Option<Progress>,
+        pub(crate) batch: Batch,
+        /// Files modified or changed after this time are skipped.
+        settled_before: Option<i64>,
+        /// First error writing to the database, after which no more files are written.
+        failure: RefCell<Option<DatabaseError>>,
+    }
+
+    /// What indexing does with a file found during traversal.
+    #[derive(Debug, PartialEq, Eq, Hash)]
+    pub(crate) enum FilePlan {
+        Add,
+        /// Hash the file again, as it changed or lacks block signatures.
+        Update,
+        /// Give the entry with this key the path of the file, which moved there unmodified.
+        Move(String),
+        Skip,
+        /// Leave the file for a later run, as it was modified too recently and may still be written to.
+        Recent,
+    }
+
+    pub(crate) fn plan_file(db: &Database, key: &String, metadata: &Metadata, block_size: Option<u64>, settled_before: Option<i64>) -> Result<FilePlan, DatabaseError> {
+        if settled_before.is_some_and(|settled_before| metadata.mtime() > settled_before || metadata.ctime() > settled_before) {
+            debug!("modified too recently -> {:?}", key);
+            return Ok(FilePlan::Recent);
+        }
+        let plan = match db.get_entry(key) {
+            Ok(entry) => {
+                if has_changed(metadata, &entry) {
+                    debug!("found, but file updated. -> {:?}", entry);
+                    FilePlan::Update
+                } else if block_size.is_some() && entry.size > 0 && entry.link_target.is_none() && !db.has_blocks(key)? {
+                    debug!("found, but no block signatures yet. -> {:?}", entry);
+                    FilePlan::Update
+                } else {
+                    debug!("already found -> {:?}", entry);
+                    FilePlan::Skip
+                }
+            },
+            Err(DatabaseError::EntryNotFound) => match find_moved_entry(db, metadata)? {
+                Some(entry) => {
+                    debug!("not found, but moved from -> {:?}", entry);
+                    FilePlan::Move(entry.path)
+                },
+                None => FilePlan::Add,
+            },
+            Err(any) => {
+                error!("Cannot look up the entry of {:?} -> {}", key, any);
+                return Err(any);
+            }
+        };
+        Ok(plan)
+    }
+
+    /// Finds the entry of a file which moved to a new path, by the device and inode of the file.
+    ///
+    /// Only matches when the old path no longer exists, and the file was not modified since it was hashed.
+    fn find_moved_entry(db: &Database, metadata: &Metadata) -> Result<Option<Entry>, DatabaseError> {
+        if !metadata.is_file() {
+            return Ok(None);
+        }
+        Ok(db.find_by_inode(metadata.dev(), metadata.ino())?.into_iter().find(|entry| {
+            entry.link_target.is_none()
+                && entry.size == metadata.len()
+                && entry.timestamp == metadata.mtime()
+                && entry.timestamp_nsec.is_none_or(|nsec| nsec == metadata.mtime_nsec())
+                && metadata.mtime() < entry.updated as i64
+                && fs::symlink_metadata(&entry.abspath).is_err_and(|any| any.kind() == ErrorKind::NotFound)
+        }))
+    }
+
+    /// Gets the metadata of a file found during traversal, or of the file a followed link points to.
+    fn file_metadata(dir_entry: &DirEntry, symlinks: SymlinkPolicy) -> Result<Metadata, Error> {
+        // symlinks only reach this point when they are either recorded or followed.
+        let is_link = dir_entry.file_type().is_ok_and(|file_type| file_type.is_symlink());
+        match is_link && symlinks == SymlinkPolicy::Follow {
+            true => fs::metadata(dir_entry.path()),
+            false => dir_entry.metadata(),
+        }
+    }
+
+    /// Gives the time files have to be last modified or changed before, to be indexed.
+    fn settled_before(options: &IndexingOptions, now_timestamp: u64) -> Option<i64> {
+        options.min_age.map(|min_age| now_timestamp as i64 - min_age as i64)
+    }
+
+    pub fn new_filter(root: &Path, options: &IndexingOptions) -> Result<PathFilter, Error> {
+        PathFilter::new(root, &options.includes, &options.excludes).map_err(|why| {
+            error!("Invalid include or exclude pattern -> {}", why);
+            Error::new(ErrorKind::InvalidInput, why)
+        })
+    }
+
+    fn new_indexing_filter(root: &Path, options: &IndexingOptions) -> Result<PathFilter, IndexingError> {
+        new_filter(root, options).map_err(|any| IndexingError::ExecutionError(any, "Invalid include or exclude pattern".to_string()))
+    }
+
+    /// Indexes the files under the root directory into the database, creating it when needed.
+    ///
+    /// A run stopped once the duration has passed still returns a summary, which is not
+    /// `completed`. Errors reading files are recorded in the database and counted, while an
+    /// error reading or writing the database stops the run.
+    pub fn index(output_file: &Path, root_dir: &Path, options: &IndexingOptions) -> Result<IndexingSummary, IndexingError> {
+        let root = verify_root_path(root_dir)?;
+        if options.dry_run {
+            return dry_run(output_file, root, options);
+        }
 
         let now_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
 
-        let connection = Connection::open(output_file).unwrap();
-        let db = Database::new(&connection);
-        db.init_for(root.to_str().unwrap(), now_timestamp, options.no_sync).unwrap();
+        let connection = Connection::open(output_file)?;
+        let mut db = Database::new(&connection);
+        db.init_for(root.to_str().unwrap(), options.name.as_deref(), now_timestamp, options.no_sync, options.algorithm)?;
+        let algorithm = db.get_metadata(None)?.algorithm;
+        info!("Using hash algorithm: {}", algorithm);
+        let run_id = db.start_run("index", now_timestamp, options.read_mode)?;
+        if let Some(block_size) = options.block_size {
+            db.set_block_size(Some(block_size).filter(|block_size| *block_size > 0))?;
+        }
+        let block_size = db.get_block_size()?;
+        if let Some(block_size) = block_size {
+            info!("Storing signatures of {} byte blocks.", block_size);
+        }
+        let hash_options = HashOptions {
+            algorithm,
+            read_mode: options.read_mode,
+            block_size,
+            retries: options.retries,
+        };
+
+        let filter = new_indexing_filter(root, options)?;
+        let traversal_options = TraversalOptions::new(&filter, options.symlinks, options.one_file_system);
 
-        let delete_count: i64 = match options.skip_delete_check {
-            false => remove_deleted_files(&db, root_dir).unwrap() as i64,
+        let resume_after = match options.resume {
+            true => db.get_checkpoint()?.map(|checkpoint| root.join(checkpoint)),
+            false => None,
+        };
+        if let Some(path) = &resume_after {
+            info!("Resuming after {:?}", path);
+        }
+        let last_visited: RefCell<Option<PathBuf>> = RefCell::new(None);
+
+        let progress = match options.progress {
             true => {
+                let (total_files, total_bytes) = prescan(root, options, &filter, resume_after.as_deref());
+                info!("Found {} files to process ({}).", total_files, format_bytes(total_bytes));
+                Some(Progress::new(total_files, total_bytes))
+            },
+            false => None,
+        };
+
+        let jobs = options.jobs.max(1);
+        let writer = IndexWriter::new(&db, root, now_timestamp, run_id, options, progress);
+        db.begin_batch()?;
+        let traversal_result = thread::scope(|scope| {
+            // Bounded, so that the traversal does not run too far ahead of the workers.
+            let (job_sender, job_receiver) = mpsc::sync_channel::<HashJob>(jobs * 2);
+            let (result_sender, result_receiver) = mpsc::channel::<HashResult>();
+
+            let job_receiver = Arc::new(Mutex::new(job_receiver));
+            for _ in 0 .. jobs {
+                let job_receiver = Arc::clone(&job_receiver);
+                let result_sender = result_sender.clone();
+                let hash_options = &hash_options;
+                let level = writer.file_level();
+                scope.spawn(move || hash_worker(root, now_timestamp, hash_options, level, &job_receiver, &result_sender));
+            }
+            drop(result_sender);
+
+            let callback: &dyn Fn(&DirEntry) = &|dir_entry| writer.attempt(|| {
+                let path_buf = dir_entry.path();
+                last_visited.replace(Some(path_buf.clone()));
+                let metadata = file_metadata(dir_entry, options.symlinks);
+                let job = match writer.prepare(path_buf, metadata, block_size)? {
+                    Some(job) => job,
+                    None => return Ok(()),
+                };
+                let is_link = dir_entry.file_type().is_ok_and(|file_type| file_type.is_symlink());
+                if is_link && options.symlinks == SymlinkPolicy::Record {
+                    let entry = link_entry(root, &job, now_timestamp, algorithm, writer.file_level());
+                    return writer.write(job, entry.map_err(ReadError::from), &[]);
+                }
+                writer.submit(job, &job_sender)?;
+
+                for result in result_receiver.try_iter() {
+                    writer.record(result)?;
+                }
+                Ok(())
+            });
+            let traversal_result = traverse(root, callback, &traversal_options, options.duration, resume_after.as_deref());
+
+            // Let the workers finish the remaining jobs.
+            drop(job_sender);
+            for result in result_receiver.iter() {
+                writer.attempt(|| writer.record(result));
+            }
+            traversal_result
+        });
+        for (path, error) in traversal_options.errors.take() {
+            writer.attempt(|| writer.record_error(&path, &ReadError::from(error)));
+        }
+        if let Some(progress) = &writer.progress {
+            progress.finish();
+        }
+        // the batch is left uncommitted, so that the next run picks up its files again.
+        if let Some(any) = writer.failure.take() {
+            return Err(IndexingError::Database(any));
+        }
+
+        let completed = match traversal_result {
+            Ok(_) => {
+                db.set_checkpoint(None)?;
+                true
+            },
+            Err(any) => {
+                warn!("Error occurred during processing. caused by: {}", any);
+                if let Some(path) = last_visited.into_inner() {
+                    let checkpoint = abspath_to_path(root, &path);
+                    info!("Saving checkpoint to resume from -> {}", checkpoint);
+                    db.set_checkpoint(Some(&checkpoint))?;
+                }
+                false
+            }
+        };
+
+        let delete_count = match (options.skip_delete_check, completed) {
+            (true, _) => {
                 info!("Skipping removal of deleted files from index.");
-                -1
+                None
+            },
+            (false, false) => {
+                info!("Skipping removal of deleted files until a full pass has finished.");
+                None
+            },
+            (false, true) => {
+                let (delete_count, move_count) = remove_deleted_files(&db, root_dir, &traversal_options, run_id, now_timestamp)?;
+                // moved files were added by the run, before they were found to be moves.
+                writer.counts.added.fetch_sub(move_count as u64, Ordering::Relaxed);
+                writer.counts.moved.fetch_add(move_count as u64, Ordering::Relaxed);
+                Some(delete_count as u64)
             },
         };
+        if let Some(keep_deleted) = options.keep_deleted {
+            let pruned = db.prune_deleted_entries(now_timestamp.saturating_sub(keep_deleted.saturating_mul(86_400)))?;
+            info!("Pruned {} records of files deleted {} or more days ago.", pruned, keep_deleted);
+        }
 
-        let add_count = AtomicU64::new(0);
-        let update_count = AtomicU64::new(0);
-        let skip_count = AtomicU64::new(0);
-        let error_count = AtomicU64::new(0);
-        let callback: &dyn Fn(&DirEntry) -> () = &|dir_entry| {
-            let path_buf = dir_entry.path();
-            let key = abspath_to_path(root_dir, &path_buf);
-            let found_entry = db.get_entry(&key);
-            match found_entry {
-                Ok(entry) => {
-                    if is_newer_than_last_write(dir_entry, &entry) {
-                        debug!("found, but file updated. -> {:?}", entry);
-                        match add_entry(&db, &root, &path_buf, dir_entry, now_timestamp) {
-                            Ok(_) => {
-                                update_count.fetch_add(1, Ordering::Relaxed);
-                            },
-                            Err(any) => {
-                                warn!("Error occurred during processing {} -> {}", path_to_string(path_buf.as_path()), any);
-                                error_count.fetch_add(1, Ordering::Relaxed);
-                            }
-                        };
-                    } else {
-                        debug!("already found -> {:?}", entry);
-                        skip_count.fetch_add(1, Ordering::Relaxed);
-                    }
+        db.commit_batch()?;
+
+        let skipped_mount_points = Vec::from_iter(traversal_options.skipped_mount_points.take());
+        let summary = writer.counts.into_summary(delete_count, skipped_mount_points, completed);
+        if summary.fallback_reads > 0 {
+            warn!("{} files were read with fadvise, as O_DIRECT is not supported.", summary.fallback_reads);
+        }
+        let finished_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        db.finish_run(run_id, finished_timestamp, summary.fallback_reads, summary.errors)?;
+
+        log_summary(&summary);
+        Ok(summary)
+    }
+
+    fn log_summary(summary: &IndexingSummary) {
+        info!(
+            "Added: {}, Updated: {}, Moved: {}, Deleted: {}, Skipped: {}, Unstable: {}, Errors: {}, Skipped mount points: {}.",
+            summary.added,
+            summary.updated,
+            summary.moved,
+            summary.deleted.map_or(-1, |deleted| deleted as i64),
+            summary.skipped,
+            summary.unstable,
+            summary.errors,
+            summary.skipped_mount_points.len()
+        );
+    }
+
+    /// Indexes only the specified paths under the root, such as the files a watch found to have
+    /// changed, hashing them on the calling thread.
+    ///
+    /// Paths which no longer exist have their entry removed, along with the entries under them
+    /// when they were directories. The paths are expected to be files not excluded by the filter.
+    pub fn index_paths(output_file: &Path, root_dir: &Path, paths: &[PathBuf], options: &IndexingOptions) -> Result<IndexingSummary, IndexingError> {
+        let root = verify_root_path(root_dir)?;
+        let now_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        let connection = Connection::open(output_file)?;
+        let mut db = Database::new(&connection);
+        db.init_for(root.to_str().unwrap(), options.name.as_deref(), now_timestamp, options.no_sync, options.algorithm)?;
+        let algorithm = db.get_metadata(None)?.algorithm;
+        let run_id = db.start_run("watch", now_timestamp, options.read_mode)?;
+        let block_size = db.get_block_size()?;
+        let hash_options = HashOptions {
+            algorithm,
+            read_mode: options.read_mode,
+            block_size,
+            retries: options.retries,
+        };
+
+        let writer = IndexWriter::new(&db, root, now_timestamp, run_id, options, None);
+        db.begin_batch()?;
+        // files are handled before removals, so that the old path of a moved file is not removed.
+        let (existing, vanished): (Vec<&PathBuf>, Vec<&PathBuf>) = paths.iter()
+            .partition(|path| fs::symlink_metadata(path).is_ok());
+        for path_buf in existing {
+            let is_link = fs::symlink_metadata(path_buf).is_ok_and(|metadata| metadata.is_symlink());
+            let metadata = match is_link && options.symlinks == SymlinkPolicy::Follow {
+                true => fs::metadata(path_buf),
+                false => fs::symlink_metadata(path_buf),
+            };
+            let mut job = match writer.prepare(path_buf.clone(), metadata, block_size)? {
+                Some(job) => job,
+                None => continue,
+            };
+            if is_link && options.symlinks == SymlinkPolicy::Record {
+                let entry = link_entry(root, &job, now_timestamp, algorithm, writer.file_level());
+                writer.write(job, entry.map_err(ReadError::from), &[])?;
+                continue;
+            }
+            let result = match hash_entry(root, &mut job, now_timestamp, &hash_options, writer.file_level()) {
+                Ok((entry, hashed)) => HashResult { job, entry: Ok(entry), blocks: hashed.blocks, read_mode: hashed.read_mode },
+                Err(any) => HashResult { job, entry: Err(any), blocks: Vec::new(), read_mode: options.read_mode },
+            };
+            writer.record(result)?;
+        }
+
+        let mut removed_keys = Vec::new();
+        for path_buf in vanished {
+            removed_keys.extend(db.select_paths_under(&abspath_to_path(root, path_buf))?);
+        }
+        let (delete_count, move_count) = remove_entries(&db, &removed_keys, run_id, now_timestamp)?;
+        writer.counts.added.fetch_sub(move_count as u64, Ordering::Relaxed);
+        writer.counts.moved.fetch_add(move_count as u64, Ordering::Relaxed);
+        db.commit_batch()?;
+
+        let summary = writer.counts.into_summary(Some(delete_count as u64), Vec::new(), true);
+        let finished_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        db.finish_run(run_id, finished_timestamp, summary.fallback_reads, summary.errors)?;
+
+        log_summary(&summary);
+        Ok(summary)
+    }
+
+    /// Counts the files and bytes the traversal will visit, for showing progress.
+    fn prescan(root: &Path, options: &IndexingOptions, filter: &PathFilter, resume_after: Option<&Path>) -> (u64, u64) {
+        // separate options, so that errors and skipped mount points are only reported by the run.
+        let traversal_options = TraversalOptions::new(filter, options.symlinks, options.one_file_system);
+        let totals: RefCell<(u64, u64)> = RefCell::new((0, 0));
+        let callback: &dyn Fn(&DirEntry) = &|dir_entry| {
+            let size = file_metadata(dir_entry, options.symlinks).map_or(0, |metadata| metadata.len());
+            let mut totals = totals.borrow_mut();
+            totals.0 += 1;
+            totals.1 += size;
+        };
+        if let Err(any) = traverse(root, callback, &traversal_options, None, resume_after) {
+            warn!("Error occurred while counting files. caused by: {}", any);
+        }
+        totals.into_inner()
+    }
+
+    /// Opens an existing database read-only for a dry run, or gives `None` when the database or the
+    /// root does not exist yet.
+    fn open_read_only(output_file: &Path, root: &Path, options: &IndexingOptions) -> Result<Option<Connection>, IndexingError> {
+        if !output_file.exists() {
+            return Ok(None);
+        }
+        let connection = Connection::open_with_flags(output_file, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        {
+            let mut db = Database::new(&connection);
+            db.create_compatibility_view()?;
+            match db.select_root_for(root.to_str().unwrap(), options.name.as_deref()) {
+                Ok(()) => {},
+                Err(DatabaseError::RootNotFound(name)) => {
+                    info!("Root '{}' is not in the database yet.", name);
+                    return Ok(None);
                 },
-                Err(DatabaseError::EntryNotFound) => {
-                    match add_entry(&db, &root, &path_buf, dir_entry, now_timestamp) {
-                        Ok(_) => {
-                            add_count.fetch_add(1, Ordering::Relaxed);
-                        },
-                        Err(any) => {
-                            warn!("Error occurred during processing {} -> {}", path_to_string(path_buf.as_path()), any);
-                            error_count.fetch_add(1, Ordering::Relaxed);
-                        }
-                    };
+                Err(any) => {
+                    error!("Cannot index {:?} into the existing database -> {}", root, any);
+                    return Err(IndexingError::Database(any));
                 },
-                Err(_any) => {
-                    error!("Something went wrong! -> {:?}", key);
-                    panic!("Something went wrong! -> {:?}", key);
+            }
+        }
+        Ok(Some(connection))
+    }
+
+    /// Walks the tree and reports what indexing would do, without hashing files or writing to the
+    /// database. A database or root which does not exist yet is not created.
+    ///
+    /// Always plans a full pass, ignoring the duration and resume options. The summary gives the
+    /// number of files which would be handled, with unreadable files counted as errors.
+    fn dry_run(output_file: &Path, root: &Path, options: &IndexingOptions) -> Result<IndexingSummary, IndexingError> {
+        let now_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        // plans against an empty database when there is no database or root yet.
+        let (connection, existing) = match open_read_only(output_file, root, options)? {
+            Some(connection) => (connection, true),
+            None => (Connection::open_in_memory()?, false),
+        };
+        let mut db = Database::new(&connection);
+        match existing {
+            true => db.select_root_for(root.to_str().unwrap(), options.name.as_deref())?,
+            false => db.init_for(root.to_str().unwrap(), options.name.as_deref(), now_timestamp, false, options.algorithm)?,
+        }
+        let block_size = match options.block_size {
+            Some(block_size) => Some(block_size).filter(|block_size| *block_size > 0),
+            None => db.get_block_size()?,
+        };
+
+        let filter = new_indexing_filter(root, options)?;
+        let traversal_options = TraversalOptions::new(&filter, options.symlinks, options.one_file_system);
+
+        // number of files and bytes for each plan.
+        let planned: RefCell<HashMap<FilePlan, (u64, u64)>> = RefCell::new(HashMap::new());
+        let errors = Cell::new(0);
+        let failure: RefCell<Option<DatabaseError>> = RefCell::new(None);
+        let callback: &dyn Fn(&DirEntry) = &|dir_entry| {
+            if failure.borrow().is_some() {
+                return;
+            }
+            let path_buf = dir_entry.path();
+            let metadata = match file_metadata(dir_entry, options.symlinks) {
+                Ok(any) => any,
+                Err(any) => {
+                    warn!("Error occurred during processing {} -> {}", path_to_string(&path_buf), any);
+                    errors.set(errors.get() + 1);
+                    return;
                 }
+            };
+            let plan = match plan_file(&db, &abspath_to_path(root, &path_buf), &metadata, block_size, settled_before(options, now_timestamp)) {
+                Ok(any) => any,
+                Err(any) => {
+                    failure.replace(Some(any));
+                    return;
+                }
+            };
+            match &plan {
+                FilePlan::Add => info!("Would add -> {} ({} B)", path_to_string(&path_buf), metadata.len()),
+                FilePlan::Update => info!("Would re-hash -> {} ({} B)", path_to_string(&path_buf), metadata.len()),
+                FilePlan::Move(from_key) => info!("Would move -> {} to {} ({} B)", from_key, path_to_string(&path_buf), metadata.len()),
+                FilePlan::Recent => info!("Would skip recently modified -> {} ({} B)", path_to_string(&path_buf), metadata.len()),
+                FilePlan::Skip => {},
             }
+            let mut planned = planned.borrow_mut();
+            let (count, bytes) = planned.entry(plan).or_default();
+            *count += 1;
+            *bytes += metadata.len();
         };
-        match traverse(root, callback, Some(options)) {
-            Ok(_) => { /* nothing to do */ }
-            Err(any) => {
-                warn!("Error occurred during processing. caused by: {}", any);
+        if let Err(any) = traverse(root, callback, &traversal_options, None, None) {
+            warn!("Error occurred during processing. caused by: {}", any);
+        }
+        if let Some(any) = failure.into_inner() {
+            return Err(IndexingError::Database(any));
+        }
+
+        let planned = planned.into_inner();
+        let mut removed: (u64, u64) = (0, 0);
+        if !options.skip_delete_check {
+            for key in find_deleted_files(&db, root, &traversal_options)? {
+                if planned.contains_key(&FilePlan::Move(key.clone())) {
+                    continue;
+                }
+                let entry = db.get_entry(&key)?;
+                info!("Would remove -> {} ({} B)", entry.abspath, entry.size);
+                removed.0 += 1;
+                removed.1 += entry.size;
             }
         }
 
+        let [added, updated, skipped, recent] = [FilePlan::Add, FilePlan::Update, FilePlan::Skip, FilePlan::Recent]
+            .map(|plan| planned.get(&plan).copied().unwrap_or_default());
+        let moved = planned.iter()
+            .filter(|(plan, _)| matches!(plan, FilePlan::Move(_)))
+            .fold((0, 0), |total, (_, (count, bytes))| (total.0 + count, total.1 + bytes));
         info!(
-            "Added: {}, Updated: {}, Deleted: {}, Skipped: {}, Errors: {}.",
-            add_count.into_inner(),
-            update_count.into_inner(),
-            delete_count,
-            skip_count.into_inner(),
-            error_count.into_inner()
+            "Would add: {} ({} B), Re-hash: {} ({} B), Move: {} ({} B), Remove: {} ({} B), Skip: {} ({} B), Recent: {} ({} B).",
+            added.0, added.1, updated.0, updated.1, moved.0, moved.1, removed.0, removed.1, skipped.0, skipped.1, recent.0, recent.1
         );
-        Ok(())
+        let traversal_errors = traversal_options.errors.take().len() as u64;
+        Ok(IndexingSummary {
+            added: added.0,
+            updated: updated.0,
+            moved: moved.0,
+            deleted: Some(removed.0).filter(|_| !options.skip_delete_check),
+            skipped: skipped.0,
+            unstable: recent.0,
+            errors: errors.get() + traversal_errors,
+            fallback_reads: 0,
+            skipped_mount_points: Vec::from_iter(traversal_options.skipped_mount_points.take()),
+            completed: true,
+        })
     }
 
-    fn is_newer_than_last_write(dir_entry: &DirEntry, entry: &Entry) -> bool {
-        let last_written_time = entry.updated;
-        let modified_time = dir_entry.metadata().unwrap().modified().unwrap();
-        let mod_timestamp = modified_time.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    /// Checks whether the file may have changed since the entry was written.
+    ///
+    /// Besides comparing the size, modification time, status change time and inode, a file
+    /// modified in or after the second it was hashed is treated as changed, as it could have
+    /// been written to after being read without its modification time changing ("racily clean".)
+    /// Even the nanoseconds cannot tell then, as file systems update the modification time at a
+    /// coarser granularity. Without the time of hashing, the time the run started is taken.
+    /// Attributes not recorded by older versions are not compared.
+    pub(crate) fn has_changed(metadata: &Metadata, entry: &Entry) -> bool {
+        let is_racily_clean = metadata.mtime() >= entry.hashed.unwrap_or(entry.updated) as i64;
 
-        // if file changed since last indexing, then return true
-        last_written_time < mod_timestamp
+        metadata.len() != entry.size
+            || metadata.mtime() != entry.timestamp
+            || entry.timestamp_nsec.is_some_and(|nsec| nsec != metadata.mtime_nsec())
+            || entry.ctime.is_some_and(|ctime| ctime != metadata.ctime())
+            || entry.ctime_nsec.is_some_and(|nsec| nsec != metadata.ctime_nsec())
+            || entry.inode.is_some_and(|inode| inode != metadata.ino())
+            || is_racily_clean
     }
 
-    fn add_entry(db: &Database, root: &Path, path_buf: &PathBuf, dir_entry: &DirEntry, now_timestamp: u64) -> Result<(), Error> {
-        let modified_time = dir_entry.metadata().unwrap().modified().unwrap();
-        let mod_timestamp = modified_time.duration_since(UNIX_EPOCH).unwrap().as_secs();
-        let size = dir_entry.metadata().unwrap().len();
+    fn hash_worker(root: &Path, now_timestamp: u64, options: &HashOptions, level: Level, jobs: &Mutex<Receiver<HashJob>>, results: &Sender<HashResult>) {
+        loop {
+            let mut job = match jobs.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => break, // no more jobs.
+            };
+            let result = match hash_entry(root, &mut job, now_timestamp, options, level) {
+                Ok((entry, hashed)) => HashResult { job, entry: Ok(entry), blocks: hashed.blocks, read_mode: hashed.read_mode },
+                Err(any) => HashResult { job, entry: Err(any), blocks: Vec::new(), read_mode: options.read_mode },
+            };
+            results.send(result).unwrap();
+        }
+    }
 
+    /// Hashes the file of the job, checking that it did not change while it was read, as the
+    /// signature of a torn read would not match the file. A file which changed is hashed again,
+    /// and given up on as unstable when it keeps changing. The hashed file is logged at the given level.
+    pub(crate) fn hash_entry(root: &Path, job: &mut HashJob, now_timestamp: u64, options: &HashOptions, level: Level) -> Result<(Entry, FileSignature), ReadError> {
         let start_time = SystemTime::now();
 
-        let hash = String::from_utf8(hash_file(&path_buf)?.to_vec()).unwrap();
-        let entry = Entry::new(&path_buf, root, &hash, size, mod_timestamp, now_timestamp);
+        let mut attempt = 0;
+        let (hashed, hashed_timestamp) = loop {
+            let hashed_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let hashed = hash_file(&job.path_buf, options)?;
+            let metadata = fs::metadata(&job.path_buf)?;
+            if !job.has_changed(&metadata) {
+                break (hashed, hashed_timestamp);
+            }
+            if attempt == UNSTABLE_RETRIES {
+                job.unstable = true;
+                return Err(ReadError::from(Error::other("File kept changing while being read")));
+            }
+            attempt += 1;
+            debug!("File changed while being read, hashing it again ({}/{}) -> {:?}", attempt, UNSTABLE_RETRIES, job.path_buf);
+            *job = HashJob {
+                links: job.links,
+                link_key: job.link_key,
+                ..HashJob::new(job.path_buf.clone(), &metadata, job.is_update)
+            };
+        };
+        let entry = Entry { hashed: Some(hashed_timestamp), ..job.to_entry(root, &hashed.signature, now_timestamp) };
         let duration = SystemTime::now().duration_since(start_time).unwrap().as_micros();
-        let processing_rate = size as f64 / duration as f64;
+        let processing_rate = job.size as f64 / duration as f64;
+
+        log!(level, "Processed in {} ms @ {} MB/s, adding entry -> {:?}", duration / 1000, processing_rate, entry);
+        Ok((entry, hashed))
+    }
 
-        info!("Processed in {} ms @ {} MB/s, adding entry -> {:?}", duration / 1000, processing_rate, entry);
-        db.add_entry(&entry);
+    /// Creates an entry for a symbolic link, using the hash of its target as the signature.
+    fn link_entry(root: &Path, job: &HashJob, now_timestamp: u64, algorithm: HashAlgorithm, level: Level) -> Result<Entry, Error> {
+        let target = path_to_string(&fs::read_link(&job.path_buf)?);
+        let mut hasher = algorithm.hasher();
+        hasher.update(target.as_bytes());
 
-        Ok(())
+        let mut entry = job.to_entry(root, &hasher.finalize(), now_timestamp);
+        entry.link_target = Some(target);
+        log!(level, "Adding symlink entry -> {:?}", entry);
+        Ok(entry)
     }
 
-    fn hash_file(path: &PathBuf) -> Result<[u8; 64], Error> {
-        let mut file = fs::File::open(&path)?;
-        let mut hasher = Sha256::new();
-        let _n = io::copy(&mut file, &mut hasher).unwrap();
-        let hash = hasher.finalize();
+    impl<'a> IndexWriter<'a> {
+        pub(crate) fn new(db: &'a Database, root: &'a Path, now_timestamp: u64, run_id: i64, options: &IndexingOptions, progress: Option<Progress>) -> IndexWriter<'a> {
+            IndexWriter {
+                db,
+                root,
+                now_timestamp,
+                run_id,
+                read_mode: options.read_mode,
+                counts: IndexingCounts::default(),
+                hardlinks: RefCell::new(HashMap::new()),
+                progress,
+                batch: Batch::new(options),
+                settled_before: settled_before(options, now_timestamp),
+                failure: RefCell::new(None),
+            }
+        }
 
-        let mut hex_hash = [0u8; 64];
-        let _res = match base16ct::lower::encode_str(&hash, &mut hex_hash) {
-            Err(why) => {
-                error!("Error occurred during stringifying the hash. Caused by {}", why);
-                panic!("Error occurred during stringifying the hash. Caused by {}", why);
-            },
-            Ok(res) => res
-        };
+        /// Level to log each file at, which is only shown with debug logging while progress is
+        /// shown, as the lines would break up the progress line.
+        fn file_level(&self) -> Level {
+            match self.progress {
+                Some(_) => Level::Debug,
+                None => Level::Info,
+            }
+        }
 
-        Ok(hex_hash)
+        /// Runs the database writes for a file, unless an earlier write failed. The first failure
+        /// is kept, to be returned once the workers have finished.
+        fn attempt(&self, write: impl FnOnce() -> Result<(), DatabaseError>) {
+            if self.failure.borrow().is_some() {
+                return;
+            }
+            if let Err(any) = write() {
+                error!("Error while writing to the database, stopping the run -> {}", any);
+                self.failure.replace(Some(any));
+            }
+        }
+
+        /// Decides what to do with a file found during traversal, giving the job to hash it if
+        /// it needs to be hashed. Otherwise the file is skipped, moved or recorded as an error.
+        pub(crate) fn prepare(&self, path_buf: PathBuf, metadata: Result<Metadata, Error>, block_size: Option<u64>) -> Result<Option<HashJob>, DatabaseError> {
+            let metadata = match metadata {
+                Ok(any) => any,
+                Err(any) => {
+                    // such as when the file was removed during the traversal.
+                    self.record_error(&path_buf, &ReadError::from(any))?;
+                    self.processed(0)?;
+                    return Ok(None);
+                }
+            };
+
+            let key = abspath_to_path(self.root, &path_buf);
+            let is_update = match plan_file(self.db, &key, &metadata, block_size, self.settled_before)? {
+                FilePlan::Add => false,
+                FilePlan::Update => true,
+                FilePlan::Move(from_key) => {
+                    self.move_entry(&from_key, &path_buf, &metadata)?;
+                    return Ok(None);
+                },
+                FilePlan::Skip => {
+                    self.counts.skipped.fetch_add(1, Ordering::Relaxed);
+                    self.processed(metadata.len())?;
+                    return Ok(None);
+                },
+                FilePlan::Recent => {
+                    log!(self.file_level(), "Skipping recently modified file -> {}", path_to_string(&path_buf));
+                    self.counts.unstable.fetch_add(1, Ordering::Relaxed);
+                    self.processed(metadata.len())?;
+                    return Ok(None);
+                }
+            };
+
+            Ok(Some(HashJob::new(path_buf, &metadata, is_update)))
+        }
+
+        /// Sends the file to the hashing workers, unless another link to it has already been sent.
+        pub(crate) fn submit(&self, job: HashJob, job_sender: &SyncSender<HashJob>) -> Result<(), DatabaseError> {
+            if job.links > 1 {
+                let mut hardlinks = self.hardlinks.borrow_mut();
+                match hardlinks.get_mut(&job.link_key) {
+                    Some(HardlinkState::Pending(waiting)) => {
+                        waiting.push(job);
+                        return Ok(());
+                    },
+                    Some(HardlinkState::Hashed(signature, blocks)) => {
+                        debug!("reusing signature of hard link -> {:?}", job.path_buf);
+                        let entry = job.to_entry(self.root, signature, self.now_timestamp);
+                        let blocks = blocks.clone();
+                        drop(hardlinks);
+                        return self.write(job, Ok(entry), &blocks);
+                    },
+                    None => {
+                        hardlinks.insert(job.link_key, HardlinkState::Pending(Vec::new()));
+                    }
+                }
+            }
+            job_sender.send(job).unwrap();
+            Ok(())
+        }
+
+        /// Writes a hashed file, along with any other links to it waiting for the signature.
+        pub(crate) fn record(&self, result: HashResult) -> Result<(), DatabaseError> {
+            if result.read_mode != self.read_mode {
+                self.counts.fallback_reads.fetch_add(1, Ordering::Relaxed);
+            }
+            let job = &result.job;
+            // when the file was replaced while it was hashed, the signature is not that of the other links.
+            let replaced = (job.device, job.inode) != job.link_key;
+            let waiting = match job.links > 1 {
+                true => {
+                    let mut hardlinks = self.hardlinks.borrow_mut();
+                    let state = match &result.entry {
+                        Ok(entry) if !replaced => hardlinks.insert(job.link_key, HardlinkState::Hashed(entry.signature.clone(), result.blocks.clone())),
+                        _ => hardlinks.remove(&job.link_key),
+                    };
+                    match state {
+                        Some(HardlinkState::Pending(waiting)) => waiting,
+                        _ => Vec::new(),
+                    }
+                },
+                false => Vec::new(),
+            };
+
+            for mut waiting_job in waiting {
+                waiting_job.unstable = job.unstable || replaced;
+                let entry = match &result.entry {
+                    Ok(_) if replaced => Err(ReadError::from(Error::other("Hard link was replaced while being read"))),
+                    Ok(entry) => Ok(Entry { hashed: entry.hashed, ..waiting_job.to_entry(self.root, &entry.signature, self.now_timestamp) }),
+                    Err(any) => {
+                        let error = match any.error.raw_os_error() {
+                            Some(errno) => Error::from_raw_os_error(errno),
+                            None => Error::new(any.error.kind(), any.error.to_string()),
+                        };
+                        Err(ReadError { error, offset: any.offset, bad_ranges: any.bad_ranges.clone() })
+                    },
+                };
+                self.write(waiting_job, entry, &result.blocks)?;
+            }
+            self.write(result.job, result.entry, &result.blocks)
+        }
+
+        pub(crate) fn write(&self, job: HashJob, entry: Result<Entry, ReadError>, blocks: &[Block]) -> Result<(), DatabaseError> {
+            match entry {
+                Ok(entry) => {
+                    self.db.add_entry(&entry)?;
+                    self.db.set_blocks(&entry.path, blocks)?;
+                    match job.is_update {
+                        true => self.counts.updated.fetch_add(1, Ordering::Relaxed),
+                        false => self.counts.added.fetch_add(1, Ordering::Relaxed),
+                    };
+                },
+                Err(_) if job.unstable => {
+                    warn!("Skipping file which kept changing while being read -> {}", path_to_string(&job.path_buf));
+                    self.counts.unstable.fetch_add(1, Ordering::Relaxed);
+                },
+                Err(any) => self.record_error(&job.path_buf, &any)?,
+            }
+            self.processed(job.size)
+        }
+
+        /// Moves the entry of a file renamed since the last run to its new path, without hashing it again.
+        fn move_entry(&self, from_key: &str, path_buf: &Path, metadata: &Metadata) -> Result<(), DatabaseError> {
+            let entry = self.db.get_entry(from_key)?;
+            let renamed = Entry::new(path_buf, self.root, &entry.signature, entry.size, entry.timestamp, entry.updated);
+            let moved = Entry {
+                path: renamed.path,
+                abspath: renamed.abspath,
+                basename: renamed.basename,
+                dirname: renamed.dirname,
+                // renaming changes the ctime.
+                ctime: Some(metadata.ctime()),
+                ctime_nsec: Some(metadata.ctime_nsec()),
+                ..entry
+            };
+            log!(self.file_level(), "Moved -> {} to {}", from_key, moved.path);
+            self.db.rename_entry(from_key, &moved)?;
+            self.db.add_move(self.run_id, from_key, &moved.path, MoveMethod::Inode, self.now_timestamp)?;
+            self.counts.moved.fetch_add(1, Ordering::Relaxed);
+            self.processed(metadata.len())
+        }
+
+        /// Counts a file as processed, committing the batch of files written so far when it is
+        /// full or old enough.
+        pub(crate) fn processed(&self, bytes: u64) -> Result<(), DatabaseError> {
+            if let Some(progress) = &self.progress {
+                progress.advance(bytes);
+            }
+
+            let files = self.batch.files.get() + 1;
+            if files >= self.batch.size || self.batch.started.get().elapsed() >= self.batch.interval {
+                debug!("committing batch of {} files", files);
+                self.db.commit_batch()?;
+                self.db.begin_batch()?;
+                self.batch.files.set(0);
+                self.batch.started.set(Instant::now());
+            } else {
+                self.batch.files.set(files);
+            }
+            Ok(())
+        }
+
+        /// Records a file or directory which could not be read in the database.
+        fn record_error(&self, path: &Path, error: &ReadError) -> Result<(), DatabaseError> {
+            warn!("Error occurred during processing {} -> {}", path_to_string(path), error);
+            self.counts.errors.fetch_add(1, Ordering::Relaxed);
+            let key = abspath_to_path(self.root, path);
+            self.db.add_error(self.run_id, &key, &error.error, error.offset, self.now_timestamp)?;
+            if !error.bad_ranges.is_empty() {
+                self.db.add_bad_ranges(&key, &error.bad_ranges, self.now_timestamp)?;
+            }
+            Ok(())
+        }
     }
+
 }
 
+#[cfg(test)]
+mod indexing_tests {
+    use std::fs;
+    use std::io::Error;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use log::Level;
+    use rusqlite::Connection;
+    use crate::db::db::{Database, DatabaseError};
+    use crate::filter::filter::IGNORE_FILE_NAME;
+    use crate::hashing::hashing::{HashAlgorithm, HashOptions, ReadError, ReadMode};
+    use crate::indexing::indexing::*;
+    use crate::model::model::Entry;
+    use crate::testing::testing::TempDir;
+
+    fn options() -> IndexingOptions {
+        IndexingOptions { no_sync: true, ..Default::default() }
+    }
+
+    /// Writes a file modified well before it is hashed, so that it is not taken as racily clean.
+    fn write_settled(path: &Path, contents: impl AsRef<[u8]>) {
+        fs::write(path, contents).unwrap();
+        let modified = SystemTime::now() - Duration::from_secs(3600);
+        fs::File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    fn writer<'a>(db: &'a Database, root: &'a Path, options: &IndexingOptions) -> IndexWriter<'a> {
+        IndexWriter::new(db, root, 1000, db.start_run("index", 1000, ReadMode::Cached).unwrap(), options, None)
+    }
+
+    #[test]
+    fn hashes_with_several_workers() {
+        let temp_dir = TempDir::new("indexing-workers");
+        let root = temp_dir.join("root");
+        fs::create_dir_all(root.join("dir")).unwrap();
+        for index in 0 .. 20 {
+            write_settled(&root.join(format!("dir/{:02}", index)), format!("{}", index));
+        }
+        fs::hard_link(root.join("dir/00"), root.join("link")).unwrap();
+        let output_file = temp_dir.join("index.db");
+        let options = IndexingOptions { jobs: 4, ..options() };
+
+        let first = index(&output_file, &root, &options).unwrap();
+        let second = index(&output_file, &root, &options).unwrap();
+        let connection = Connection::open(&output_file).unwrap();
+        let mut db = Database::new(&connection);
+        db.select_root_for(root.to_str().unwrap(), None).unwrap();
+        let signatures: Vec<String> = ["dir/00", "dir/07", "link"].iter()
+            .map(|key| db.get_entry(key).unwrap().signature)
+            .collect();
+
+        assert_eq!((21, 0, 0), (first.added, first.skipped, first.errors));
+        assert_eq!((0, 21), (second.added, second.skipped));
+        let mut hasher = HashAlgorithm::Sha256.hasher();
+        hasher.update(b"0");
+        assert_eq!(hasher.finalize(), signatures[0]);
+        assert_ne!(signatures[0], signatures[1]);
+        assert_eq!(signatures[0], signatures[2]);
+    }
+
+    #[test]
+    fn ignore_files() {
+        let temp_dir = TempDir::new("indexing-ignore-files");
+        let root = temp_dir.join("root");
+        fs::create_dir_all(root.join("dir")).unwrap();
+        for name in ["a", "b", "dir/c"] {
+            write_settled(&root.join(name), name);
+        }
+        fs::write(root.join(IGNORE_FILE_NAME), "b\n").unwrap();
+        fs::write(root.join("dir").join(IGNORE_FILE_NAME), "").unwrap();
+        let output_file = temp_dir.join("index.db");
+
+        let first = index(&output_file, &root, &options()).unwrap();
+        // ignores a file which is already indexed.
+        fs::write(root.join(IGNORE_FILE_NAME), "b\na\n").unwrap();
+        let second = index(&output_file, &root, &options()).unwrap();
+        let connection = Connection::open(&output_file).unwrap();
+        let mut db = Database::new(&connection);
+        db.select_root_for(root.to_str().unwrap(), None).unwrap();
+
+        assert_eq!((2, Some(0)), (first.added, first.deleted));
+        assert_eq!((0, Some(1), 1), (second.added, second.deleted, second.skipped));
+        assert_eq!(vec![root.join("dir/c").to_str().unwrap()], db.select_all_paths().unwrap());
+    }
+
+    #[test]
+    fn commits_full_and_old_batches() {
+        let dir = TempDir::new("indexing-batches");
+        let output_file = dir.join("index.db");
+        let connection = Connection::open(&output_file).unwrap();
+        let mut db = Database::new(&connection);
+        db.init_for(dir.to_str().unwrap(), None, 1000, false, None).unwrap();
+        let reader = Connection::open(&output_file).unwrap();
+        let committed = || reader.query_row("SELECT COUNT(*) FROM entries", [], |row| row.get::<_, u64>(0)).unwrap();
+
+        db.begin_batch().unwrap();
+        let writer = writer(&db, &dir, &IndexingOptions { batch_size: 3, batch_interval: 3600, ..options() });
+        let mut counts = Vec::new();
+        for index in 0 .. 4 {
+            let name = format!("file{}", index);
+            db.add_entry(&Entry::new(&dir.join(&name), &dir, "signature", 0, 0, 1000)).unwrap();
+            writer.processed(0).unwrap();
+            counts.push((committed(), writer.batch.files.get()));
+        }
+        // as if the batch were started before the interval.
+        writer.batch.started.set(Instant::now().checked_sub(Duration::from_secs(3600)).unwrap());
+        db.add_entry(&Entry::new(&dir.join("file4"), &dir, "signature", 0, 0, 1000)).unwrap();
+        writer.processed(0).unwrap();
+        counts.push((committed(), writer.batch.files.get()));
+        db.commit_batch().unwrap();
+
+        assert_eq!(vec![(0, 1), (0, 2), (3, 0), (3, 1), (5, 0)], counts);
+    }
+
+    #[test]
+    fn dry_run_does_not_write() {
+        let temp_dir = TempDir::new("indexing-dry-run");
+        let root = temp_dir.join("root");
+        fs::create_dir(&root).unwrap();
+        for name in ["kept", "changed", "removed"] {
+            write_settled(&root.join(name), name);
+        }
+        let output_file = temp_dir.join("index.db");
+        let missing_file = temp_dir.join("missing.db");
+        index(&output_file, &root, &options()).unwrap();
+        fs::write(root.join("changed"), b"changed again").unwrap();
+        fs::remove_file(root.join("removed")).unwrap();
+        fs::write(root.join("added"), b"added").unwrap();
+        let before = fs::read(&output_file).unwrap();
+
+        let planned = index(&output_file, &root, &IndexingOptions { dry_run: true, ..options() }).unwrap();
+        let unknown = index(&missing_file, &root, &IndexingOptions { dry_run: true, ..options() }).unwrap();
+
+        assert_eq!((1, 1, Some(1), 1), (planned.added, planned.updated, planned.deleted, planned.skipped));
+        assert_eq!((3, Some(0)), (unknown.added, unknown.deleted));
+        assert!(before == fs::read(&output_file).unwrap());
+        assert!(!missing_file.exists());
+    }
+
+    #[test]
+    fn changed_files() {
+        let dir = TempDir::new("indexing-changed");
+        let path_buf = dir.join("a");
+        fs::write(&path_buf, b"hello").unwrap();
+        let modified = UNIX_EPOCH + Duration::new(1_000_000, 500);
+        fs::File::options().write(true).open(&path_buf).unwrap().set_modified(modified).unwrap();
+        let metadata = fs::metadata(&path_buf).unwrap();
+
+        // hashed in the second after the file was last modified, in a run started before.
+        let entry = || Entry { hashed: Some(1_000_001), ..HashJob::new(path_buf.clone(), &metadata, false).to_entry(&dir, "signature", 999_000) };
+        assert!(!has_changed(&metadata, &entry()));
+        assert!(has_changed(&metadata, &Entry { size: 4, ..entry() }));
+        assert!(has_changed(&metadata, &Entry { timestamp_nsec: Some(400), ..entry() }));
+        assert!(has_changed(&metadata, &Entry { inode: Some(metadata.ino() + 1), ..entry() }));
+        // a write after hashing in the same second cannot be told apart, even by the nanoseconds.
+        assert!(has_changed(&metadata, &Entry { hashed: Some(1_000_000), ..entry() }));
+        assert!(has_changed(&metadata, &Entry { hashed: None, ..entry() }));
+        assert!(!has_changed(&metadata, &Entry { hashed: None, updated: 1_000_001, ..entry() }));
+    }
+
+    #[test]
+    fn hashes_racily_clean_files_again() {
+        let temp_dir = TempDir::new("indexing-racily-clean");
+        let root = temp_dir.join("root");
+        fs::create_dir(&root).unwrap();
+        let path_buf = root.join("a");
+        let output_file = temp_dir.join("index.db");
+        fs::write(&path_buf, b"hello").unwrap();
+        index(&output_file, &root, &options()).unwrap();
+        let connection = Connection::open(&output_file).unwrap();
+        // as if hashed in the second the file was written, should the second have passed since.
+        connection.execute("UPDATE entries SET hashed = MIN(hashed, timestamp)", ()).unwrap();
+        let modified = fs::metadata(&path_buf).unwrap().modified().unwrap();
+        // rewritten with the same size and, as on a file system with coarse timestamps, the same modification time.
+        fs::write(&path_buf, b"world").unwrap();
+        fs::File::options().write(true).open(&path_buf).unwrap().set_modified(modified).unwrap();
+
+        let summary = index(&output_file, &root, &options()).unwrap();
+        let mut db = Database::new(&connection);
+        db.select_root_for(root.to_str().unwrap(), None).unwrap();
+        let entry = db.get_entry("a").unwrap();
+
+        assert_eq!((0, 1, 0), (summary.added, summary.updated, summary.skipped));
+        let mut hasher = HashAlgorithm::Sha256.hasher();
+        hasher.update(b"world");
+        assert_eq!(hasher.finalize(), entry.signature);
+        assert!(entry.hashed.is_some_and(|hashed| hashed as i64 >= entry.timestamp));
+    }
+
+    #[test]
+    fn skips_recently_changed_files() {
+        let temp_dir = TempDir::new("indexing-recent");
+        let dir = temp_dir.join("root");
+        fs::create_dir(&dir).unwrap();
+        let path_buf = dir.join("a");
+        fs::write(&path_buf, b"hello").unwrap();
+        fs::File::options().write(true).open(&path_buf).unwrap().set_modified(UNIX_EPOCH + Duration::from_secs(1_000_000)).unwrap();
+        let metadata = fs::metadata(&path_buf).unwrap();
+        let output_file = temp_dir.join("index.db");
+        let summary = index(&output_file, &dir, &IndexingOptions { min_age: Some(3600), ..options() }).unwrap();
+
+        let connection = Connection::open_in_memory().unwrap();
+        let mut db = Database::new(&connection);
+        db.init_for(dir.to_str().unwrap(), None, 1000, false, None).unwrap();
+        let key = "a".to_string();
+        // the status change time is the time the file was written, however old its modification time.
+        assert_eq!(FilePlan::Recent, plan_file(&db, &key, &metadata, None, Some(metadata.ctime() - 1)).unwrap());
+        assert_eq!(FilePlan::Add, plan_file(&db, &key, &metadata, None, Some(metadata.ctime())).unwrap());
+        assert_eq!(FilePlan::Add, plan_file(&db, &key, &metadata, None, None).unwrap());
+        assert_eq!((0, 1), (summary.added, summary.unstable));
+    }
+
+    #[test]
+    fn hashes_changed_files_again() {
+        let dir = TempDir::new("indexing-unstable");
+        let path_buf = dir.join("a");
+        fs::write(&path_buf, b"hello").unwrap();
+        let connection = Connection::open_in_memory().unwrap();
+        let mut db = Database::new(&connection);
+        db.init_for(dir.to_str().unwrap(), None, 1000, false, None).unwrap();
+        db.begin_batch().unwrap();
+        let writer = writer(&db, &dir, &options());
+        let hash_options = HashOptions { algorithm: HashAlgorithm::Sha256, read_mode: ReadMode::Cached, block_size: None, retries: None };
+
+        let mut job = HashJob::new(path_buf.clone(), &fs::metadata(&path_buf).unwrap(), false);
+        // written to after the job was created, as if while it was being read.
+        fs::write(&path_buf, b"hello, world").unwrap();
+        let hashed = hash_entry(&dir, &mut job, 1000, &hash_options, Level::Debug);
+        let mut unstable = HashJob::new(path_buf.clone(), &fs::metadata(&path_buf).unwrap(), false);
+        unstable.unstable = true;
+        writer.write(unstable, Err(ReadError::from(Error::other("File kept changing while being read"))), &[]).unwrap();
+
+        let (entry, _) = hashed.unwrap();
+        let mut hasher = HashAlgorithm::Sha256.hasher();
+        hasher.update(b"hello, world");
+        assert_eq!(hasher.finalize(), entry.signature);
+        assert_eq!((12, false), (entry.size, job.unstable));
+        // a file which kept changing is left for a later run, rather than recorded as an error.
+        assert_eq!((1, 0), (writer.counts.unstable.load(Ordering::Relaxed), writer.counts.errors.load(Ordering::Relaxed)));
+    }
+
+    #[test]
+    fn hard_link_replaced_while_hashed() {
+        let dir = TempDir::new("indexing-replaced");
+        fs::write(dir.join("a"), b"hello").unwrap();
+        fs::hard_link(dir.join("a"), dir.join("b")).unwrap();
+        let connection = Connection::open_in_memory().unwrap();
+        let mut db = Database::new(&connection);
+        db.init_for(dir.to_str().unwrap(), None, 1000, false, None).unwrap();
+        db.begin_batch().unwrap();
+        let options = options();
+        let writer = writer(&db, &dir, &options);
+        let hash_options = HashOptions { algorithm: Default::default(), read_mode: ReadMode::Cached, block_size: None, retries: None };
+
+        let (job_sender, job_receiver) = mpsc::sync_channel(2);
+        for name in ["a", "b"] {
+            let path_buf = dir.join(name);
+            let job = writer.prepare(path_buf.clone(), fs::metadata(&path_buf), None).unwrap().unwrap();
+            writer.submit(job, &job_sender).unwrap();
+        }
+        let mut job = job_receiver.try_recv().unwrap();
+        // replaced by another file after the job for it was created.
+        fs::write(dir.join("c"), b"other").unwrap();
+        fs::rename(dir.join("c"), dir.join("a")).unwrap();
+        let entry = hash_entry(&dir, &mut job, 1000, &hash_options, Level::Debug).map(|(entry, _)| entry);
+        writer.record(HashResult { job, entry, blocks: Vec::new(), read_mode: ReadMode::Cached }).unwrap();
+        let other = db.get_entry("b");
+
+        // the other link is left for the next run, rather than given the signature of the new file.
+        assert!(writer.hardlinks.borrow().is_empty());
+        assert!(matches!(other, Err(DatabaseError::EntryNotFound)));
+        assert_eq!(1, writer.counts.unstable.into_inner());
+    }
+}
@@ -0,0 +1,46 @@
+// Copyright (c) 2022-2025 Chris Kroells
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Indexes files by their signatures into SQLite databases, to find changed, missing, duplicated
+//! and corrupted files later on.
+//!
+//! The `mitsubachi` binary is a thin command line wrapper over this library.
+
+#![allow(clippy::module_inception)]
+
+pub mod benchmark;
+pub mod compare;
+pub mod db;
+pub mod filter;
+pub mod hashing;
+pub mod indexing;
+pub mod model;
+pub mod progress;
+pub mod rebase;
+pub mod scrub;
+#[cfg(test)]
+mod testing;
+pub mod traversal;
+pub mod watch;
+
+pub use crate::compare::compare::{compare, Comparison};
+pub use crate::db::db::{Database, DatabaseError, DatabaseMetadata, Difference, DupeGroup, HardlinkGroup, MissingFiles, Which};
+pub use crate::indexing::indexing::{index, IndexingError, IndexingOptions, IndexingSummary};
+pub use crate::model::model::Entry;
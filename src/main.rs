@@ -18,19 +18,20 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
-extern crate core;
-
+use std::error::Error;
 use std::path::Path;
+use std::process::ExitCode;
 use clap::{Parser, Subcommand};
-use log::{info, LevelFilter};
+use log::{error, info, warn, LevelFilter};
 use rusqlite::Connection;
-use crate::db::db::{Database, Which};
-
-mod db;
-mod model;
-mod benchmark;
-mod indexing;
-use crate::indexing::indexing::{index, IndexingOptions};
+use mitsubachi::{benchmark, rebase, scrub, Database, DatabaseError, Which};
+use mitsubachi::db::db::open_existing;
+use mitsubachi::hashing::hashing::{HashAlgorithm, ReadMode};
+use mitsubachi::indexing::indexing::{index, IndexingOptions, BATCH_INTERVAL, BATCH_SIZE};
+use mitsubachi::rebase::rebase::RebaseOptions;
+use mitsubachi::scrub::scrub::ScrubOptions;
+use mitsubachi::traversal::traversal::SymlinkPolicy;
+use mitsubachi::watch::watch::{watch, WatchOptions};
 
 #[derive(Parser)]
 #[clap(author, version, about)]
@@ -52,6 +53,10 @@ enum Commands {
         #[clap(short = 'c', long, action, default_value_t = false)]
         skip_delete_check: bool,
 
+        /// Prune records of files removed from the index after this many days. Kept forever by default.
+        #[clap(long, value_name = "DAYS")]
+        keep_deleted: Option<u64>,
+
         /// Processing duration in seconds (i.e. stops processing after N seconds.)
         #[clap(short = 'd', long, value_name = "DURATION")]
         duration: Option<u64>,
@@ -60,6 +65,74 @@ enum Commands {
         #[clap(short = 's', long, action, default_value_t = false)]
         no_sync: bool,
 
+        /// Number of files to hash concurrently.
+        #[clap(short = 'j', long, value_name = "JOBS", default_value_t = 1)]
+        jobs: usize,
+
+        /// Hash algorithm for a new database. Defaults to the algorithm of an existing database, or sha256.
+        #[clap(short = 'a', long, value_enum, value_name = "ALGORITHM")]
+        algorithm: Option<HashAlgorithm>,
+
+        /// Only index files matching the glob pattern. Can be specified multiple times.
+        #[clap(short = 'i', long = "include", value_name = "PATTERN")]
+        includes: Vec<String>,
+
+        /// Skip files and directories matching the glob pattern. Can be specified multiple times.
+        /// Patterns in `.mitsubachiignore` files are also honored, using gitignore syntax.
+        #[clap(short = 'e', long = "exclude", value_name = "PATTERN")]
+        excludes: Vec<String>,
+
+        /// How to handle symbolic links: record the link target, follow the link, or skip it.
+        #[clap(long, value_enum, value_name = "POLICY", default_value_t = SymlinkPolicy::Skip)]
+        symlinks: SymlinkPolicy,
+
+        /// Do not descend into directories on other file systems (i.e. mount points under the root directory.)
+        #[clap(short = 'x', long, action, default_value_t = false)]
+        one_file_system: bool,
+
+        /// Continue from where the last run stopped, such as when limited by duration.
+        #[clap(short = 'r', long, action, default_value_t = false)]
+        resume: bool,
+
+        /// Also store signatures of blocks of this many bytes, to locate changes within files.
+        /// The block size is kept for later runs, and 0 stops storing block signatures.
+        #[clap(short = 'b', long, value_name = "BYTES")]
+        block_size: Option<u64>,
+
+        /// Retry failed reads this many times, then skip past unreadable regions and record them instead of giving up on the file.
+        #[clap(long, value_name = "RETRIES")]
+        retries: Option<u32>,
+
+        /// How files are read while hashing: through the page cache, with O_DIRECT, or dropping them from the page cache.
+        #[clap(long, value_enum, value_name = "MODE", default_value_t = ReadMode::Cached)]
+        read_mode: ReadMode,
+
+        /// Skip files modified less than this many seconds ago, as they may still be written to.
+        #[clap(long, value_name = "SECONDS")]
+        min_age: Option<u64>,
+
+        /// Number of files to write to the database in one transaction. A crash loses at most one batch, which the next run picks up again.
+        #[clap(long, value_name = "FILES", default_value_t = BATCH_SIZE)]
+        batch_size: u64,
+
+        /// Commit the files written so far after this many seconds, even if the batch is not full.
+        #[clap(long, value_name = "SECONDS", default_value_t = BATCH_INTERVAL)]
+        batch_interval: u64,
+
+        /// Count the files to process first, and show progress with an estimated time remaining.
+        /// Each file is then only logged at debug level.
+        #[clap(short = 'p', long, action, default_value_t = false)]
+        progress: bool,
+
+        /// Only report the files which would be added, re-hashed or removed, without hashing them or writing to the database.
+        #[clap(short = 'n', long, action, default_value_t = false)]
+        dry_run: bool,
+
+        /// Name of the root in the database, which can hold several roots.
+        /// Defaults to the name of the existing root for the directory, or else its last component.
+        #[clap(long, value_name = "NAME")]
+        name: Option<String>,
+
         /// Root directory to start the scan from.
         #[clap(value_name = "ROOT_DIR")]
         root: String,
@@ -68,21 +141,156 @@ enum Commands {
         #[clap(value_name = "OUTPUT_FILE")]
         output_file: String,
     },
+    /// Watch a root directory for changes, and keep its index up to date by indexing only the files which changed.
+    Watch {
+        /// Seconds a file has to go without changes before it is indexed.
+        #[clap(long, value_name = "SECONDS", default_value_t = 2)]
+        debounce: u64,
+
+        /// Start watching without first running a full pass, when the index is known to be up to date.
+        #[clap(long, action, default_value_t = false)]
+        skip_initial_index: bool,
+
+        /// Disables database file sync operations to reduce disk I/O.
+        #[clap(short = 's', long, action, default_value_t = false)]
+        no_sync: bool,
+
+        /// Number of files to hash concurrently during full passes.
+        #[clap(short = 'j', long, value_name = "JOBS", default_value_t = 1)]
+        jobs: usize,
+
+        /// Only index files matching the glob pattern. Can be specified multiple times.
+        #[clap(short = 'i', long = "include", value_name = "PATTERN")]
+        includes: Vec<String>,
+
+        /// Skip files and directories matching the glob pattern. Can be specified multiple times.
+        #[clap(short = 'e', long = "exclude", value_name = "PATTERN")]
+        excludes: Vec<String>,
+
+        /// How to handle symbolic links: record the link target, follow the link, or skip it.
+        #[clap(long, value_enum, value_name = "POLICY", default_value_t = SymlinkPolicy::Skip)]
+        symlinks: SymlinkPolicy,
+
+        /// Do not descend into directories on other file systems (i.e. mount points under the root directory.)
+        #[clap(short = 'x', long, action, default_value_t = false)]
+        one_file_system: bool,
+
+        /// Retry failed reads this many times, then skip past unreadable regions and record them instead of giving up on the file.
+        #[clap(long, value_name = "RETRIES")]
+        retries: Option<u32>,
+
+        /// How files are read while hashing: through the page cache, with O_DIRECT, or dropping them from the page cache.
+        #[clap(long, value_enum, value_name = "MODE", default_value_t = ReadMode::Cached)]
+        read_mode: ReadMode,
+
+        /// Name of the root in the database, which can hold several roots.
+        #[clap(long, value_name = "NAME")]
+        name: Option<String>,
+
+        /// Root directory to watch.
+        #[clap(value_name = "ROOT_DIR")]
+        root: String,
+
+        /// Output file (sqlite3 database file.)
+        #[clap(value_name = "OUTPUT_FILE")]
+        output_file: String,
+    },
     /// Compare two indices
     Compare {
+        /// Root to compare in the first database. Required if it holds several roots.
+        #[clap(long, value_name = "NAME")]
+        first_root: Option<String>,
+
+        /// Root to compare in the second database. Required if it holds several roots.
+        #[clap(long, value_name = "NAME")]
+        second_root: Option<String>,
+
         #[clap(value_name = "FIRST")]
         first: String,
 
         #[clap(value_name = "SECOND")]
         second: String,
     },
+    /// Re-hash indexed files to find files that changed without their size or modification time changing.
+    #[clap(alias = "verify")]
+    Scrub {
+        /// Overwrite the stored signature of files found to be corrupted.
+        #[clap(short = 'u', long, action, default_value_t = false)]
+        update: bool,
+
+        /// Processing duration in seconds (i.e. stops processing after N seconds.)
+        #[clap(short = 'd', long, value_name = "DURATION")]
+        duration: Option<u64>,
+
+        /// Number of bytes to read before stopping.
+        #[clap(short = 'b', long, value_name = "BYTES")]
+        bytes: Option<u64>,
+
+        /// How files are read while hashing: through the page cache, with O_DIRECT, or dropping them from the page cache.
+        #[clap(long, value_enum, value_name = "MODE", default_value_t = ReadMode::Direct)]
+        read_mode: ReadMode,
+
+        /// Read files with a mismatching signature a second time, to tell corruption from unreliable reads.
+        #[clap(short = 'D', long, action, default_value_t = false)]
+        double_check: bool,
+
+        /// Retry failed reads this many times, then skip past unreadable regions and record them instead of giving up on the file.
+        #[clap(long, value_name = "RETRIES")]
+        retries: Option<u32>,
+
+        #[clap(value_name = "DATABASE_FILE")]
+        file: String
+    },
+    /// List files which repeatedly failed to be read by index or scrub.
+    Errors {
+        /// Minimum number of failures for a file to be listed.
+        #[clap(short = 'm', long, value_name = "COUNT", default_value_t = 2)]
+        min_count: u64,
+
+        #[clap(value_name = "DATABASE_FILE")]
+        file: String
+    },
+    /// Move an indexed root to a new path, such as when a disk is mounted somewhere else.
+    Rebase {
+        /// Number of files to check under the new path before rewriting the database. 0 skips the check.
+        #[clap(short = 's', long, value_name = "COUNT", default_value_t = 10)]
+        sample: u64,
+
+        /// Path the root was indexed at.
+        #[clap(value_name = "OLD")]
+        old: String,
+
+        /// Path the root is now at.
+        #[clap(value_name = "NEW")]
+        new: String,
+
+        #[clap(value_name = "DATABASE_FILE")]
+        file: String
+    },
+    /// List files removed from the index, with their last known signature and where a copy is indexed.
+    Deleted {
+        /// Only list files removed from this root.
+        #[clap(short = 'r', long, value_name = "NAME")]
+        root: Option<String>,
+
+        #[clap(value_name = "DATABASE_FILE")]
+        file: String
+    },
     /// Find possible duplicate files.
     Dupe {
+        /// Only find duplicates within this root, instead of across all roots.
+        #[clap(short = 'r', long, value_name = "NAME")]
+        root: Option<String>,
+
         #[clap(value_name = "DATABASE_FILE")]
         file: String
     },
     /// Get statistics for database file.
     Stats {
+        /// Only get statistics for this root, instead of all roots.
+        #[clap(short = 'r', long, value_name = "NAME")]
+        root: Option<String>,
+
         #[clap(value_name = "DATABASE_FILE")]
         file: String
     },
@@ -90,87 +298,297 @@ enum Commands {
     Benchmark {}
 }
 
-fn main() {
+fn main() -> ExitCode {
     let cli = Cli::parse();
 
     use env_logger::Env;
-    if cli.quiet == true {
+    if cli.quiet {
         env_logger::Builder::new().filter_level(LevelFilter::Off).init();
     } else {
         env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
     }
 
-    match &cli.command {
-        Commands::Index { skip_delete_check, duration, no_sync, root, output_file } => {
-            // TODO handle IndexingError.
+    let result = match &cli.command {
+        Commands::Index { skip_delete_check, keep_deleted, duration, no_sync, jobs, algorithm, includes, excludes, symlinks, one_file_system, resume, block_size, retries, read_mode, min_age, batch_size, batch_interval, progress, dry_run, name, root, output_file } => {
             index(
                 Path::new(output_file),
                 Path::new(root),
                 &IndexingOptions {
+                    name: name.clone(),
                     skip_delete_check: *skip_delete_check,
+                    keep_deleted: *keep_deleted,
                     duration: *duration,
-                    no_sync: *no_sync
+                    no_sync: *no_sync,
+                    jobs: *jobs,
+                    algorithm: *algorithm,
+                    includes: includes.clone(),
+                    excludes: excludes.clone(),
+                    symlinks: *symlinks,
+                    one_file_system: *one_file_system,
+                    resume: *resume,
+                    read_mode: *read_mode,
+                    block_size: *block_size,
+                    retries: *retries,
+                    min_age: *min_age,
+                    batch_size: *batch_size,
+                    batch_interval: *batch_interval,
+                    progress: *progress,
+                    dry_run: *dry_run
                 }
-            ).unwrap();
+            ).map(|_summary| ()).map_err(|any| format!("Could not index -> {}", any).into())
         },
-        Commands::Compare { first, second} => {
-            compare(first, second);
+        Commands::Watch { debounce, skip_initial_index, no_sync, jobs, includes, excludes, symlinks, one_file_system, retries, read_mode, name, root, output_file } => {
+            let indexing_options = IndexingOptions {
+                name: name.clone(),
+                no_sync: *no_sync,
+                jobs: *jobs,
+                includes: includes.clone(),
+                excludes: excludes.clone(),
+                symlinks: *symlinks,
+                one_file_system: *one_file_system,
+                read_mode: *read_mode,
+                retries: *retries,
+                ..Default::default()
+            };
+            let options = WatchOptions { debounce: *debounce, skip_initial_index: *skip_initial_index };
+            watch(Path::new(output_file), Path::new(root), &options, &indexing_options)
+                .map_err(|any| format!("Stopped watching -> {}", any).into())
         },
-        Commands::Dupe { file} => {
-            dupe(Path::new(file));
+        Commands::Compare { first_root, second_root, first, second } => {
+            compare(first, second, first_root.as_deref(), second_root.as_deref())
         },
-        Commands::Stats { file} => {
-            stats(Path::new(file));
+        Commands::Scrub { update, duration, bytes, read_mode, double_check, retries, file } => {
+            scrub(
+                Path::new(file),
+                &ScrubOptions {
+                    update: *update,
+                    duration: *duration,
+                    bytes: *bytes,
+                    read_mode: *read_mode,
+                    double_check: *double_check,
+                    retries: *retries
+                }
+            )
+        },
+        Commands::Errors { min_count, file } => {
+            errors(Path::new(file), *min_count)
+        },
+        Commands::Rebase { sample, old, new, file } => {
+            rebase::rebase::rebase(Path::new(file), old, new, &RebaseOptions { sample: *sample })
+                .map(|_rewritten| ())
+                .map_err(|any| format!("Could not rebase -> {}", any).into())
+        },
+        Commands::Deleted { root, file } => {
+            deleted(Path::new(file), root.as_deref())
+        },
+        Commands::Dupe { root, file } => {
+            dupe(Path::new(file), root.as_deref())
+        },
+        Commands::Stats { root, file } => {
+            stats(Path::new(file), root.as_deref())
         },
         Commands::Benchmark {} => {
             benchmark::benchmark::benchmark();
+            Ok(())
+        }
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(any) => {
+            error!("{}", any);
+            ExitCode::FAILURE
         }
     }
 }
 
-fn compare(first: &String, second: &String) {
-    let connection = Connection::open(Path::new(first)).unwrap();
-    let database = Database::new(&connection);
-    database.bind_second(second);
-
-    info!("Files in first: {}", database.get_count(Some(Which::First)).unwrap());
-    info!("Files in second: {}", database.get_count(Some(Which::Second)).unwrap());
+fn compare(first: &str, second: &str, first_root: Option<&str>, second_root: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let comparison = match mitsubachi::compare(Path::new(first), Path::new(second), first_root, second_root) {
+        Ok(any) => any,
+        Err(DatabaseError::AlgorithmMismatch(first_algorithm, second_algorithm)) => {
+            return Err(format!(
+                "Cannot compare indices using different hash algorithms ({} and {}). Re-index one of them with '--algorithm {}' to a new database.",
+                first_algorithm, second_algorithm, first_algorithm
+            ).into());
+        },
+        Err(any) => return Err(format!("Could not compare indices -> {}", any).into()),
+    };
 
-    let missing_files = database.find_missing().unwrap();
-    let missing_in_first = missing_files.0;
-    let missing_in_second = missing_files.1;
-    info!("Missing in first ({}): {:?}", database.get_metadata(Some(Which::First)).unwrap().path, missing_in_first);
-    info!("Missing in second ({}): {:?}", database.get_metadata(Some(Which::Second)).unwrap().path, missing_in_second);
+    info!("Files in first: {}", comparison.first_count);
+    info!("Files in second: {}", comparison.second_count);
+    info!("Missing in first ({}): {:?}", comparison.first.path, comparison.missing.missing_in_first);
+    info!("Missing in second ({}): {:?}", comparison.second.path, comparison.missing.missing_in_second);
 
     info!("Differences:");
-    for entry in database.compare().unwrap() {
-        info!("{:?}", entry);
+    for difference in &comparison.differences {
+        info!(
+            "{}: {} ({}, modified: {}) != {} ({}, modified: {})",
+            difference.path,
+            difference.first_abspath, difference.first_signature, difference.first_timestamp,
+            difference.second_abspath, difference.second_signature, difference.second_timestamp
+        );
+        if let Some(ranges) = &difference.differing_ranges {
+            info!("Differing byte ranges in {}: {:?}", difference.path, ranges);
+        }
     }
 
     info!("OK");
+    Ok(())
 }
 
-fn stats(file: &Path) {
-    let connection = Connection::open(file).unwrap();
-    let database = Database::new(&connection);
+fn scrub(file: &Path, options: &ScrubOptions) -> Result<(), Box<dyn Error>> {
+    let report = scrub::scrub::scrub(file, options).map_err(|any| format!("Could not scrub -> {}", any))?;
 
-    let entries_in_file = database.get_count(Some(Which::First)).unwrap();
+    info!(
+        "Verified: {}, Corrupted: {}, Flapping: {}, First read wrong: {}, Modified: {}, Missing: {}, Errors: {}, Remaining: {}.",
+        report.verified,
+        report.corrupted.len(),
+        report.flapping.len(),
+        report.first_read_wrong,
+        report.modified,
+        report.missing,
+        report.errors,
+        report.remaining
+    );
+    if report.fallback_reads > 0 {
+        warn!("{} files were read with fadvise, as O_DIRECT is not supported.", report.fallback_reads);
+    }
+    for corruption in &report.corrupted {
+        match &corruption.ranges {
+            Some(ranges) => warn!("Corrupted: {} (changed byte ranges: {:?})", corruption.path, ranges),
+            None => warn!("Corrupted: {}", corruption.path),
+        }
+    }
+    for path in &report.flapping {
+        warn!("Flapping: {}", path);
+    }
+    Ok(())
+}
+
+/// Opens a database for reading, selecting the given root or leaving all roots selected.
+///
+/// The connection is read-only, so a database created by an older version is read through
+/// compatibility views rather than upgraded.
+fn open_database<'a>(connection: &'a Connection, root: Option<&str>) -> Result<Database<'a>, Box<dyn Error>> {
+    let mut database = Database::new(connection);
+    database.create_compatibility_view().map_err(|any| format!("Cannot read database -> {}", any))?;
+    if let Some(root) = root {
+        database.select_root(None, Some(root)).map_err(|any| format!("Cannot select root -> {}", any))?;
+    }
+    Ok(database)
+}
+
+fn stats(file: &Path, root: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let connection = open_existing(file, true).map_err(|any| format!("Cannot open {:?} -> {}", file, any))?;
+    let database = open_database(&connection, root)?;
+
+    for metadata in database.get_roots(None)? {
+        if root.is_some_and(|root| root != metadata.name) {
+            continue;
+        }
+        info!("Root directory: {} -> {} (last updated: {})", metadata.name, metadata.path, metadata.last_updated);
+    }
+    info!("Hash algorithm: {}", database.get_metadata(None)?.algorithm);
+    if let Some(run) = database.get_last_run()? {
+        info!(
+            "Last run: {} (started: {}, finished: {}, read mode: {}, fallback reads: {}, errors: {})",
+            run.command,
+            run.started,
+            run.finished.map_or("unfinished".to_string(), |finished| finished.to_string()),
+            run.read_mode,
+            run.fallback_reads.unwrap_or(0),
+            run.errors.unwrap_or(0)
+        );
+    }
+
+    for (status, count) in database.count_by_verify_status()? {
+        info!("Entries with verify status {}: {}", status, count);
+    }
+
+    let entries_in_file = database.get_count(Some(Which::First))?;
     info!("Entries in file: {}", entries_in_file);
 
-    let size_in_bytes = database.get_size().unwrap();
+    let size_in_bytes = database.get_size()?;
     let size_in_mb = size_in_bytes / 1000000;
     info!("Total indexed file size: {} B ({} MB)", size_in_bytes, size_in_mb);
 
     let average_file_size = size_in_bytes as f64 / entries_in_file as f64;
     info!("Average file size: {} B ({} MB)", average_file_size, average_file_size / 1E6);
+    Ok(())
+}
+
+fn errors(file: &Path, min_count: u64) -> Result<(), Box<dyn Error>> {
+    let connection = open_existing(file, true).map_err(|any| format!("Cannot open {:?} -> {}", file, any))?;
+    let mut database = open_database(&connection, None)?;
+
+    let failing_files = database.find_failing_files(min_count)?;
+    info!("Files failing at least {} times: {}", min_count, failing_files.len());
+    for failing in failing_files {
+        let offset = failing.offset.map_or(String::new(), |offset| format!(" at byte {}", offset));
+        warn!(
+            "{}: {} failed {} times (first: {}, last: {}) -> {}{} ({}, errno {})",
+            failing.root,
+            failing.path,
+            failing.count,
+            failing.first_failed,
+            failing.last_failed,
+            failing.message,
+            offset,
+            failing.kind,
+            failing.errno.map_or("-".to_string(), |errno| errno.to_string())
+        );
+        database.select_root(None, Some(&failing.root))?;
+        let bad_ranges = database.get_bad_ranges(&failing.path)?;
+        if !bad_ranges.is_empty() {
+            warn!("{} has unreadable byte ranges: {:?}", failing.path, bad_ranges);
+        }
+    }
+    Ok(())
+}
+
+fn deleted(file: &Path, root: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let connection = open_existing(file, true).map_err(|any| format!("Cannot open {:?} -> {}", file, any))?;
+    let database = open_database(&connection, root)?;
+
+    let deleted_entries = database.get_deleted_entries()?;
+    info!("Deleted files: {}", deleted_entries.len());
+    for deleted in deleted_entries {
+        info!(
+            "{}: {} deleted at {} (signature: {}, size: {} B, modified: {})",
+            deleted.root, deleted.abspath, deleted.deleted, deleted.signature, deleted.size, deleted.timestamp
+        );
+        if deleted.link_target.is_some() {
+            continue;
+        }
+        for copy in database.find_by_signature(&deleted.signature)? {
+            info!("  Copy of {} -> {}", deleted.path, copy.abspath);
+        }
+    }
+    Ok(())
 }
 
-fn dupe(file: &Path) {
-    let connection = Connection::open(file).unwrap();
-    let database = Database::new(&connection);
+fn dupe(file: &Path, root: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let connection = open_existing(file, true).map_err(|any| format!("Cannot open {:?} -> {}", file, any))?;
+    let database = open_database(&connection, root)?;
 
-    let dupes = database.find_dupes();
-    info!("Dupes: {:?}", dupes);
+    let dupes = database.find_dupes()?;
+    info!("Dupes: {}", dupes.len());
+    for group in dupes {
+        info!("{} ({} B):", group.signature, group.entries[0].size);
+        for entry in group.entries {
+            info!("  {}", entry.abspath);
+        }
+    }
+
+    let hardlinks = database.find_hardlinks()?;
+    info!("Hardlinks: {}", hardlinks.len());
+    for group in hardlinks {
+        info!("Device {}, inode {}:", group.device, group.inode);
+        for entry in group.entries {
+            info!("  {}", entry.abspath);
+        }
+    }
+    Ok(())
 }
 
 #[test]
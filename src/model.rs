@@ -20,7 +20,8 @@
 
 pub mod model {
     use std::ffi::OsStr;
-    use std::path::{Path, PathBuf};
+    use std::ops::Range;
+    use std::path::Path;
     use log::warn;
 
     #[derive(Debug)]
@@ -33,15 +34,125 @@ pub mod model {
         pub basename: String,
         /// Directory name of the file.
         pub dirname: String,
-        /// SHA-256 signature of the file contents.
+        /// Signature of the file contents, as a lowercase hex string made with the hash algorithm
+        /// of the database.
         pub signature: String,
         /// File size.
         pub size: u64,
         /// Timestamp of the file.
-        /// This should be the modification time (i.e., mtime).
-        pub timestamp: u64,
+        /// This should be the modification time (i.e., mtime), and can be before the epoch.
+        pub timestamp: i64,
         /// The time this entry has been updated.
         pub updated: u64,
+        /// Target of the symbolic link, if this entry is for a link rather than a file.
+        pub link_target: Option<String>,
+        /// Device number of the file system holding the file.
+        pub device: Option<u64>,
+        /// Inode number of the file, shared by all hard links to it.
+        pub inode: Option<u64>,
+        /// Nanoseconds part of the modification time.
+        pub timestamp_nsec: Option<i64>,
+        /// Status change time of the file (i.e., ctime).
+        pub ctime: Option<i64>,
+        /// Nanoseconds part of the status change time.
+        pub ctime_nsec: Option<i64>,
+        /// The time the file started being read for the signature, which is later than `updated`
+        /// for files hashed well into a run. Not recorded by older versions, nor for hard links
+        /// taking the signature of a link hashed earlier.
+        pub hashed: Option<u64>,
+    }
+
+    /// Result of the last verification of an entry's contents.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum VerifyStatus {
+        Verified,
+        /// The signature did not match, and the file was not read again.
+        Mismatch,
+        /// The signature did not match, and reading the file again gave the same signature.
+        StableMismatch,
+        /// Reading the file again gave yet another signature, pointing at unstable hardware.
+        Flapping,
+        /// Reading the file again matched the stored signature, so the first read was wrong.
+        FirstReadWrong,
+        /// The size or modification time changed since indexing, so the file was not read.
+        Modified,
+        /// The file no longer exists.
+        Missing,
+        /// Reading the file failed.
+        Unreadable,
+    }
+
+    impl VerifyStatus {
+        pub fn name(&self) -> &'static str {
+            match self {
+                VerifyStatus::Verified => "verified",
+                VerifyStatus::Mismatch => "mismatch",
+                VerifyStatus::StableMismatch => "stable-mismatch",
+                VerifyStatus::Flapping => "flapping",
+                VerifyStatus::FirstReadWrong => "first-read-wrong",
+                VerifyStatus::Modified => "modified",
+                VerifyStatus::Missing => "missing",
+                VerifyStatus::Unreadable => "unreadable",
+            }
+        }
+    }
+
+    /// How a file which moved to a new path was recognized as the same file.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum MoveMethod {
+        /// The new path has the device and inode of the vanished path, and was not modified.
+        Inode,
+        /// The new path has the size, modification time and signature of the vanished path.
+        Signature,
+    }
+
+    impl MoveMethod {
+        pub fn name(&self) -> &'static str {
+            match self {
+                MoveMethod::Inode => "inode",
+                MoveMethod::Signature => "signature",
+            }
+        }
+    }
+
+    /// Signature of a fixed size block of a file, to locate which part of the file changed.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Block {
+        /// Byte offset of the block in the file.
+        pub offset: u64,
+        /// Length of the block, which is shorter than the block size for the last block.
+        pub length: u64,
+        pub signature: String,
+    }
+
+    /// Finds the byte ranges where two lists of blocks of the same file differ.
+    ///
+    /// Returns `None` when either list is empty or they were made with different block sizes,
+    /// as the blocks cannot be compared then. Adjacent differing blocks are merged into one range,
+    /// as are the overlapping ones found when one of the lists is a single, shorter block.
+    pub fn differing_ranges(first: &[Block], second: &[Block]) -> Option<Vec<Range<u64>>> {
+        if first.is_empty() || second.is_empty() {
+            return None;
+        }
+        let is_multi_block = first.len() > 1 && second.len() > 1;
+        if is_multi_block && first[0].length != second[0].length {
+            return None;
+        }
+
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        for index in 0 .. first.len().max(second.len()) {
+            let range = match (first.get(index), second.get(index)) {
+                (Some(first), Some(second)) if first == second => continue,
+                (Some(first), Some(second)) => first.offset .. first.offset + first.length.max(second.length),
+                (Some(block), None) | (None, Some(block)) => block.offset .. block.offset + block.length,
+                (None, None) => break,
+            };
+            match ranges.last_mut() {
+                Some(last) if last.end >= range.start => last.end = last.end.max(range.end),
+                _ => ranges.push(range),
+            }
+        }
+        Some(ranges)
     }
 
     pub fn path_to_string(path: &Path) -> String {
@@ -63,7 +174,7 @@ pub mod model {
     }
 
     impl Entry {
-        pub fn new(path_buf: &PathBuf, root: &Path, hash: &String, size: u64, mod_timestamp: u64, now_timestamp: u64) -> Entry {
+        pub fn new(path_buf: &Path, root: &Path, hash: &str, size: u64, mod_timestamp: i64, now_timestamp: u64) -> Entry {
             Entry {
                 path: abspath_to_path(root, path_buf),
                 abspath: path_to_string(path_buf),
@@ -73,11 +184,19 @@ pub mod model {
                 size,
                 timestamp: mod_timestamp,
                 updated: now_timestamp,
+                link_target: None,
+                device: None,
+                inode: None,
+                timestamp_nsec: None,
+                ctime: None,
+                ctime_nsec: None,
+                hashed: None,
             }
         }
 
         #[cfg(test)]
-        pub fn new_simple(path: &str, abspath: &str, basename: &str, dirname: &str, signature: &str, size: u64, mod_timestamp: u64, now_timestamp: u64) -> Entry {
+        #[allow(clippy::too_many_arguments)]
+        pub fn new_simple(path: &str, abspath: &str, basename: &str, dirname: &str, signature: &str, size: u64, mod_timestamp: i64, now_timestamp: u64) -> Entry {
             Entry {
                 path: String::from(path),
                 abspath: String::from(abspath),
@@ -87,7 +206,57 @@ pub mod model {
                 size,
                 timestamp: mod_timestamp,
                 updated: now_timestamp,
+                link_target: None,
+                device: None,
+                inode: None,
+                timestamp_nsec: None,
+                ctime: None,
+                ctime_nsec: None,
+                hashed: None,
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod block_tests {
+    use std::ops::Range;
+    use crate::model::model::{differing_ranges, Block};
+
+    fn blocks(signatures: &[&str], block_size: u64, size: u64) -> Vec<Block> {
+        signatures.iter().enumerate().map(|(index, signature)| {
+            let offset = index as u64 * block_size;
+            Block { offset, length: block_size.min(size - offset), signature: signature.to_string() }
+        }).collect()
+    }
+
+    #[test]
+    fn merges_adjacent_ranges() {
+        let first = blocks(&["a", "b", "c", "d", "e"], 4, 18);
+        let second = blocks(&["a", "x", "x", "d", "x"], 4, 18);
+        assert_eq!(Some(vec![4 .. 12, 16 .. 18]), differing_ranges(&first, &second));
+    }
+
+    #[test]
+    fn includes_blocks_of_longer_file() {
+        let first = blocks(&["a", "b"], 4, 8);
+        let second = blocks(&["a", "b", "c"], 4, 10);
+        assert_eq!(Some(vec![Range { start: 8, end: 10 }]), differing_ranges(&first, &second));
+    }
+
+    #[test]
+    fn merges_overlapping_ranges() {
+        // a single block can be compared with blocks of any size.
+        let first = blocks(&["a"], 16, 10);
+        let second = blocks(&["x", "y", "z"], 4, 10);
+        assert_eq!(Some(vec![Range { start: 0, end: 10 }]), differing_ranges(&first, &second));
+        assert_eq!(Some(vec![Range { start: 0, end: 10 }]), differing_ranges(&second, &first));
+    }
+
+    #[test]
+    fn different_block_sizes() {
+        let first = blocks(&["a", "b"], 4, 8);
+        let second = blocks(&["a", "b", "c", "d"], 2, 8);
+        assert_eq!(None, differing_ranges(&first, &second));
+    }
+}
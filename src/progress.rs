@@ -0,0 +1,163 @@
+// Copyright (c) 2022-2025 Chris Kroells
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+pub mod progress {
+    use std::cell::RefCell;
+    use std::io::{IsTerminal, Write};
+    use std::time::{Duration, Instant};
+    use log::info;
+
+    /// Minimum time between redrawing the progress line on a terminal.
+    const DRAW_INTERVAL: Duration = Duration::from_millis(200);
+    /// Time between progress log lines when stderr is not a terminal.
+    const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+    /// Progress of a run over a known number of files and bytes, found by a pre-scan.
+    ///
+    /// Progress is drawn as a single updating line when stderr is a terminal, and logged
+    /// periodically otherwise.
+    pub struct Progress {
+        total_files: u64,
+        total_bytes: u64,
+        started: Instant,
+        is_terminal: bool,
+        state: RefCell<ProgressState>,
+    }
+
+    #[derive(Default)]
+    struct ProgressState {
+        files: u64,
+        bytes: u64,
+        /// Time and bytes done when progress was last shown, to compute the current throughput.
+        last_shown: Option<(Instant, u64)>,
+    }
+
+    impl Progress {
+        pub fn new(total_files: u64, total_bytes: u64) -> Progress {
+            Progress {
+                total_files,
+                total_bytes,
+                started: Instant::now(),
+                is_terminal: std::io::stderr().is_terminal(),
+                state: RefCell::new(ProgressState::default()),
+            }
+        }
+
+        /// Counts a file as done, showing the progress if it is time to.
+        pub fn advance(&self, bytes: u64) {
+            let mut state = self.state.borrow_mut();
+            state.files += 1;
+            state.bytes += bytes;
+
+            let now = Instant::now();
+            let interval = match self.is_terminal {
+                true => DRAW_INTERVAL,
+                false => LOG_INTERVAL,
+            };
+            let (last_time, last_bytes) = state.last_shown.unwrap_or((self.started, 0));
+            let elapsed = now.duration_since(last_time);
+            if elapsed < interval {
+                return;
+            }
+            let throughput = (state.bytes - last_bytes) as f64 / elapsed.as_secs_f64();
+            state.last_shown = Some((now, state.bytes));
+            self.show(&state, now, throughput);
+        }
+
+        /// Shows the final progress with the average throughput, ending the progress line on a terminal.
+        pub fn finish(&self) {
+            let state = self.state.borrow();
+            let now = Instant::now();
+            let average = state.bytes as f64 / now.duration_since(self.started).as_secs_f64();
+            self.show(&state, now, average);
+            if self.is_terminal {
+                eprintln!();
+            }
+        }
+
+        fn show(&self, state: &ProgressState, now: Instant, throughput: f64) {
+            // the ETA uses the average throughput, which is steadier than the current one.
+            let average = state.bytes as f64 / now.duration_since(self.started).as_secs_f64();
+            let remaining = self.total_bytes.saturating_sub(state.bytes);
+            let eta = match average > 0.0 {
+                true => format_duration(Duration::from_secs_f64(remaining as f64 / average)),
+                false => "-".to_string(),
+            };
+            let percent = match self.total_bytes {
+                0 => 100.0,
+                total => state.bytes as f64 * 100.0 / total as f64,
+            };
+            let line = format!(
+                "{}/{} files, {}/{} ({:.1}%), {}/s, ETA {}",
+                state.files, self.total_files,
+                format_bytes(state.bytes), format_bytes(self.total_bytes), percent,
+                format_bytes(throughput as u64), eta
+            );
+            match self.is_terminal {
+                true => {
+                    let mut stderr = std::io::stderr().lock();
+                    let _ = write!(stderr, "\r\x1b[K{}", line);
+                    let _ = stderr.flush();
+                },
+                false => info!("Progress: {}", line),
+            }
+        }
+    }
+
+    /// Formats a number of bytes with a binary unit, such as `1.5 GiB`.
+    pub fn format_bytes(bytes: u64) -> String {
+        const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+        let mut value = bytes as f64;
+        let mut unit = 0;
+        while value >= 1024.0 && unit < UNITS.len() - 1 {
+            value /= 1024.0;
+            unit += 1;
+        }
+        match unit {
+            0 => format!("{} B", bytes),
+            _ => format!("{:.1} {}", value, UNITS[unit]),
+        }
+    }
+
+    /// Formats a duration as hours, minutes and seconds, such as `1:02:03`.
+    pub fn format_duration(duration: Duration) -> String {
+        let seconds = duration.as_secs();
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    }
+}
+
+#[cfg(test)]
+mod progress_tests {
+    use std::time::Duration;
+    use crate::progress::progress::{format_bytes, format_duration};
+
+    #[test]
+    fn formats_bytes() {
+        assert_eq!("512 B", format_bytes(512));
+        assert_eq!("1.5 KiB", format_bytes(1536));
+        assert_eq!("2.0 TiB", format_bytes(2 * 1024 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn formats_duration() {
+        assert_eq!("0:00:59", format_duration(Duration::from_secs(59)));
+        assert_eq!("26:03:04", format_duration(Duration::from_secs(26 * 3600 + 3 * 60 + 4)));
+    }
+}
@@ -0,0 +1,198 @@
+// Copyright (c) 2022-2025 Chris Kroells
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+pub mod rebase {
+    use std::{fmt, fs};
+    use std::fmt::Formatter;
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::fs::MetadataExt;
+    use std::path::{Path, PathBuf};
+    use log::{debug, error, info, warn};
+
+    use crate::db::db::{open_existing, Database, DatabaseError};
+    use crate::hashing::hashing::{hash_file, HashOptions, ReadMode};
+    use crate::model::model::{path_to_string, Entry};
+
+    pub struct RebaseOptions {
+        /// Number of files to check under the new path before rewriting the database.
+        /// 0 skips the check.
+        pub sample: u64,
+    }
+
+    #[derive(Debug)]
+    pub enum RebaseError {
+        /// Reading or writing the database failed.
+        Database(DatabaseError),
+        /// The new path is not a directory which can be read.
+        Io(Error),
+        /// A root with the given name is already indexed at the new path.
+        PathTaken(String, String),
+        /// Files of the sample differ under the new path, giving the number of mismatches and of
+        /// sampled files.
+        SampleMismatch(u64, u64),
+    }
+
+    impl fmt::Display for RebaseError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            match self {
+                RebaseError::Database(e) => write!(f, "{}", e),
+                RebaseError::Io(e) => write!(f, "I/O error: {}", e),
+                RebaseError::PathTaken(name, path) => write!(f, "Root '{}' is already for '{}'", name, path),
+                RebaseError::SampleMismatch(mismatches, sampled) => write!(f, "{} of {} sampled files differ", mismatches, sampled),
+            }
+        }
+    }
+
+    impl std::error::Error for RebaseError {}
+
+    impl From<DatabaseError> for RebaseError {
+        fn from(error: DatabaseError) -> Self {
+            RebaseError::Database(error)
+        }
+    }
+
+    /// Moves the root indexed at the old path to the new path, such as when a disk is mounted
+    /// somewhere else, keeping all entries.
+    ///
+    /// A random sample of files is looked up and hashed under the new path first, and the database
+    /// is left untouched unless all of them match, so that the new path is known to hold the same
+    /// data. Returns the number of entries rewritten.
+    ///
+    /// Both paths are compared with the paths of the roots as canonical paths, so that they can
+    /// be given with a trailing slash or as relative paths.
+    pub fn rebase(database_file: &Path, old_path: &str, new_path: &str, options: &RebaseOptions) -> Result<u64, RebaseError> {
+        if !Path::new(new_path).is_dir() {
+            error!("New root directory does not exist or is not a directory: {}", new_path);
+            return Err(RebaseError::Io(Error::new(ErrorKind::NotFound, format!("Not a directory: {}", new_path))));
+        }
+        let (old_path, new_path) = (canonical_path(old_path), canonical_path(new_path));
+        let new_path = new_path.as_str();
+
+        let connection = open_existing(database_file, false)?;
+        let mut db = Database::new(&connection);
+        db.upgrade_tables()?;
+        let roots = db.get_roots(None)?;
+        if let Some(existing) = roots.iter().find(|metadata| canonical_path(&metadata.path) == new_path) {
+            error!("Root '{}' is already for '{}'", existing.name, new_path);
+            return Err(RebaseError::PathTaken(existing.name.clone(), new_path.to_string()));
+        }
+        match roots.iter().find(|metadata| canonical_path(&metadata.path) == old_path) {
+            Some(metadata) => db.select_root(None, Some(&metadata.name))?,
+            None => {
+                error!("No root for '{}' in the database.", old_path);
+                return Err(DatabaseError::RootNotFound(old_path).into());
+            }
+        }
+        let algorithm = db.get_metadata(None)?.algorithm;
+
+        let hash_options = HashOptions { algorithm, read_mode: ReadMode::Cached, block_size: None, retries: None };
+        let sample = db.select_sample(options.sample)?;
+        let mut mismatches: u64 = 0;
+        for entry in &sample {
+            if !matches_entry(Path::new(new_path), entry, &hash_options) {
+                mismatches += 1;
+            }
+        }
+        if mismatches > 0 {
+            error!("{} of {} sampled files differ under '{}', leaving the database as is.", mismatches, sample.len(), new_path);
+            return Err(RebaseError::SampleMismatch(mismatches, sample.len() as u64));
+        }
+        info!("All {} sampled files match under '{}'", sample.len(), new_path);
+
+        let rewritten = db.rebase_root(new_path)?;
+        info!("Moved root from '{}' to '{}', rewriting {} entries.", old_path, new_path, rewritten);
+        Ok(rewritten)
+    }
+
+    /// Gives the path without a trailing slash or `.` components, resolved to an absolute path
+    /// without symbolic links when it exists, such as the old root still being mounted.
+    fn canonical_path(path: &str) -> String {
+        match fs::canonicalize(path) {
+            Ok(canonical) => path_to_string(&canonical),
+            Err(_) => path_to_string(&Path::new(path).components().collect::<PathBuf>()),
+        }
+    }
+
+    /// Checks the file of an entry under the new root has the same size, modification time and signature.
+    fn matches_entry(new_root: &Path, entry: &Entry, options: &HashOptions) -> bool {
+        let path = new_root.join(&entry.path);
+        let metadata = match fs::metadata(&path) {
+            Ok(any) => any,
+            Err(any) => {
+                warn!("Cannot find sampled file under the new root -> {:?} ({})", path, any);
+                return false;
+            }
+        };
+        if metadata.len() != entry.size || metadata.mtime() != entry.timestamp {
+            warn!("Sampled file differs in size or modification time -> {:?}", path);
+            return false;
+        }
+        match hash_file(&path, options) {
+            Ok(hashed) if hashed.signature == entry.signature => {
+                debug!("Sampled file matches -> {:?}", path);
+                true
+            },
+            Ok(_hashed) => {
+                warn!("Sampled file differs in signature -> {:?}", path);
+                false
+            },
+            Err(any) => {
+                warn!("Cannot read sampled file -> {:?} ({})", path, any);
+                false
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod rebase_tests {
+    use std::fs;
+    use rusqlite::Connection;
+    use crate::db::db::Database;
+    use crate::indexing::indexing::{index, IndexingOptions};
+    use crate::rebase::rebase::{rebase, RebaseOptions};
+    use crate::testing::testing::TempDir;
+
+    #[test]
+    fn paths_are_canonicalized() {
+        let temp_dir = TempDir::new("rebase-paths");
+        let (old, new) = (temp_dir.join("old"), temp_dir.join("new"));
+        fs::create_dir_all(old.join("dir")).unwrap();
+        fs::write(old.join("dir/a"), b"hello").unwrap();
+        fs::write(old.join("b"), b"hello, world").unwrap();
+        let output_file = temp_dir.join("index.db");
+        index(&output_file, &old, &IndexingOptions { no_sync: true, ..Default::default() }).unwrap();
+        // as if the disk were mounted somewhere else.
+        fs::rename(&old, &new).unwrap();
+
+        let old_path = format!("{}/", old.to_str().unwrap());
+        let new_path = format!("{}/./new/", temp_dir.to_str().unwrap());
+        let rewritten = rebase(&output_file, &old_path, &new_path, &RebaseOptions { sample: 10 }).unwrap();
+        let connection = Connection::open(&output_file).unwrap();
+        let mut db = Database::new(&connection);
+        let new = fs::canonicalize(&new).unwrap();
+        db.select_root_for(new.to_str().unwrap(), None).unwrap();
+
+        assert_eq!(2, rewritten);
+        let entry = db.get_entry("dir/a").unwrap();
+        assert_eq!((new.join("dir/a").to_str().unwrap(), new.join("dir").to_str().unwrap()), (entry.abspath.as_str(), entry.dirname.as_str()));
+        assert_eq!(new.join("b").to_str().unwrap(), db.get_entry("b").unwrap().abspath);
+    }
+}
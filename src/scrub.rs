@@ -0,0 +1,394 @@
+// Copyright (c) 2022-2025 Chris Kroells
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+pub mod scrub {
+    use std::{fmt, fs};
+    use std::fmt::Formatter;
+    use std::io::{Error, ErrorKind};
+    use std::ops::{Add, Range};
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use log::{debug, info, warn};
+
+    use crate::db::db::{open_existing, Database, DatabaseError};
+    use crate::hashing::hashing::{hash_file, FileSignature, HashOptions, ReadError, ReadMode};
+    use crate::model::model::{differing_ranges, path_to_string, Block, Entry, VerifyStatus};
+
+    pub struct ScrubOptions {
+        /// Overwrite the stored signature of files found to be corrupted.
+        pub update: bool,
+        /// Processing duration in seconds (i.e. stops processing after N seconds.)
+        pub duration: Option<u64>,
+        /// Number of bytes to read before stopping.
+        pub bytes: Option<u64>,
+        /// How files are read from storage while hashing.
+        pub read_mode: ReadMode,
+        /// Read files with a mismatching signature again, bypassing the page cache, to tell real
+        /// changes from unreliable reads.
+        pub double_check: bool,
+        /// Number of times to retry failed reads, before skipping past unreadable regions and
+        /// recording them. When `None`, a file is given up on at the first failed read.
+        pub retries: Option<u32>,
+    }
+
+    /// A file whose signature changed while the size and modification time did not.
+    #[derive(Debug)]
+    pub struct Corruption {
+        pub path: String,
+        /// Byte ranges of the file which changed, when block signatures are stored for it.
+        pub ranges: Option<Vec<Range<u64>>>,
+    }
+
+    #[derive(Debug, Default)]
+    pub struct ScrubReport {
+        /// Number of files whose contents matched the stored signature.
+        pub verified: u64,
+        /// Files whose signature changed while the size and modification time did not.
+        /// With double checking, only files which gave the same signature when read again.
+        pub corrupted: Vec<Corruption>,
+        /// Files which gave a different signature on every read.
+        pub flapping: Vec<String>,
+        /// Number of files whose first read mismatched, but matched when read again.
+        pub first_read_wrong: u64,
+        /// Number of files that changed size or modification time since indexing.
+        pub modified: u64,
+        /// Number of indexed files that no longer exist.
+        pub missing: u64,
+        /// Number of files that could not be read.
+        pub errors: u64,
+        /// Number of files left unverified because the duration or byte budget ran out.
+        pub remaining: u64,
+        /// Number of files read with `fadvise` because `O_DIRECT` was not supported.
+        pub fallback_reads: u64,
+    }
+
+    #[derive(Debug)]
+    pub enum ScrubError {
+        /// Reading or writing the database failed.
+        Database(DatabaseError),
+        /// The database file could not be found.
+        Io(Error),
+    }
+
+    impl fmt::Display for ScrubError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            match self {
+                ScrubError::Database(e) => write!(f, "{}", e),
+                ScrubError::Io(e) => write!(f, "I/O error: {}", e),
+            }
+        }
+    }
+
+    impl std::error::Error for ScrubError {}
+
+    impl From<DatabaseError> for ScrubError {
+        fn from(error: DatabaseError) -> Self {
+            ScrubError::Database(error)
+        }
+    }
+
+    pub(crate) enum Outcome {
+        Verified,
+        Corrupted(FileSignature),
+        /// Corrupted, and reading the file again gave the same signature.
+        StableMismatch(FileSignature),
+        /// Reading the file again gave yet another signature.
+        Flapping,
+        /// Reading the file again matched the stored signature.
+        FirstReadWrong,
+        Modified,
+        Missing,
+    }
+
+    /// Re-hashes entries in the database and checks them against the stored signature.
+    ///
+    /// A file whose signature differs while its size and modification time stayed the same has
+    /// changed without going through the file system, which is what bit-rot looks like.
+    ///
+    /// Entries of all roots are processed starting from the least recently verified, so that runs
+    /// limited by duration or bytes eventually cover the whole index.
+    pub fn scrub(database_file: &Path, options: &ScrubOptions) -> Result<ScrubReport, ScrubError> {
+        if !database_file.is_file() {
+            return Err(ScrubError::Io(Error::new(ErrorKind::NotFound, format!("No database at {:?}", database_file))));
+        }
+        let now_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let terminate_at = options.duration.map(|duration| SystemTime::now().add(Duration::from_secs(duration)));
+
+        let connection = open_existing(database_file, false)?;
+        let mut db = Database::new(&connection);
+        db.upgrade_tables()?;
+        let algorithm = db.get_metadata(None)?.algorithm;
+        let run_id = db.start_run("scrub", now_timestamp, options.read_mode)?;
+
+        let entries = db.select_entries_by_last_verified()?;
+        let total = entries.len();
+
+        let mut report = ScrubReport::default();
+        let mut bytes_read: u64 = 0;
+        for (position, (root, entry)) in entries.into_iter().enumerate() {
+            let timed_out = terminate_at.is_some_and(|terminate_at| SystemTime::now() > terminate_at);
+            let bytes_exhausted = options.bytes.is_some_and(|bytes| bytes_read >= bytes);
+            if timed_out || bytes_exhausted {
+                report.remaining = (total - position) as u64;
+                info!("Scrub budget exhausted, {} entries left for the next run.", report.remaining);
+                break;
+            }
+
+            db.select_root(None, Some(&root))?;
+            let stored_blocks = db.get_blocks(None, &entry.path)?;
+            // blocks are hashed with the block size used for this file, even if changed since.
+            let block_size = stored_blocks.first().map(|block| block.length);
+
+            let hash_options = HashOptions {
+                algorithm,
+                read_mode: options.read_mode,
+                block_size,
+                retries: options.retries,
+            };
+
+            let outcome = match verify_entry(&entry, &hash_options, &mut report) {
+                Ok(Outcome::Corrupted(hashed)) if options.double_check => {
+                    bytes_read += entry.size;
+                    double_check(&entry, &hash_options, hashed, &mut report)
+                },
+                any => any,
+            };
+            // every attempt is recorded, so that files which cannot be verified do not stay at the
+            // front of the queue, keeping later runs from reaching the others.
+            let status = match &outcome {
+                Ok(Outcome::Verified) => VerifyStatus::Verified,
+                Ok(Outcome::Corrupted(_)) => VerifyStatus::Mismatch,
+                Ok(Outcome::StableMismatch(_)) => VerifyStatus::StableMismatch,
+                Ok(Outcome::Flapping) => VerifyStatus::Flapping,
+                Ok(Outcome::FirstReadWrong) => VerifyStatus::FirstReadWrong,
+                Ok(Outcome::Modified) => VerifyStatus::Modified,
+                Ok(Outcome::Missing) => VerifyStatus::Missing,
+                Err(_) => VerifyStatus::Unreadable,
+            };
+            if !matches!(status, VerifyStatus::Modified | VerifyStatus::Missing) {
+                bytes_read += entry.size;
+            }
+            db.set_last_verified(&entry.path, now_timestamp, status)?;
+
+            match outcome {
+                Ok(Outcome::Verified) => {
+                    debug!("verified -> {}", entry.path);
+                    report.verified += 1;
+                },
+                Ok(Outcome::Corrupted(hashed)) | Ok(Outcome::StableMismatch(hashed)) => {
+                    warn!("Signature mismatch without modification -> {} (expected: {}, actual: {})", entry.abspath, entry.signature, hashed.signature);
+                    let ranges = differing_ranges(&stored_blocks, &hashed.blocks);
+                    if let Some(ranges) = &ranges {
+                        warn!("Changed byte ranges -> {} {:?}", entry.abspath, ranges);
+                    }
+                    if options.update {
+                        info!("Updating signature -> {}", entry.path);
+                        db.update_signature(&entry.path, &hashed.signature, now_timestamp)?;
+                        update_blocks(&db, &entry, &stored_blocks, &hashed.blocks)?;
+                    }
+                    report.corrupted.push(Corruption { path: entry.abspath, ranges });
+                },
+                Ok(Outcome::Flapping) => {
+                    warn!("Signature differs on every read, the storage may be unreliable -> {}", entry.abspath);
+                    report.flapping.push(entry.abspath);
+                },
+                Ok(Outcome::FirstReadWrong) => {
+                    warn!("Signature mismatch on first read only -> {}", entry.abspath);
+                    report.first_read_wrong += 1;
+                },
+                Ok(Outcome::Modified) => {
+                    info!("Modified since indexing, skipping -> {}", entry.abspath);
+                    report.modified += 1;
+                },
+                Ok(Outcome::Missing) => {
+                    info!("No longer exists, skipping -> {}", entry.abspath);
+                    report.missing += 1;
+                },
+                Err(any) => {
+                    warn!("Error occurred during processing {} -> {}", entry.abspath, any);
+                    db.add_error(run_id, &entry.path, &any.error, any.offset, now_timestamp)?;
+                    if !any.bad_ranges.is_empty() {
+                        db.add_bad_ranges(&entry.path, &any.bad_ranges, now_timestamp)?;
+                    }
+                    report.errors += 1;
+                }
+            }
+        }
+
+        let finished_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        db.finish_run(run_id, finished_timestamp, report.fallback_reads, report.errors)?;
+
+        Ok(report)
+    }
+
+    fn update_blocks(db: &Database, entry: &Entry, stored_blocks: &[Block], blocks: &[Block]) -> Result<(), DatabaseError> {
+        if !stored_blocks.is_empty() {
+            db.set_blocks(&entry.path, blocks)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a file with a mismatching signature again, bypassing the page cache, so that the
+    /// second read comes from storage.
+    pub(crate) fn double_check(entry: &Entry, options: &HashOptions, first: FileSignature, report: &mut ScrubReport) -> Result<Outcome, ReadError> {
+        debug!("Signature mismatch, reading again -> {}", entry.abspath);
+        let options = HashOptions { read_mode: ReadMode::Direct, ..*options };
+        let hashed = hash_file(Path::new(&entry.abspath), &options)?;
+        if hashed.read_mode != ReadMode::Direct {
+            report.fallback_reads += 1;
+        }
+
+        if hashed.signature == first.signature {
+            Ok(Outcome::StableMismatch(hashed))
+        } else if hashed.signature == entry.signature {
+            Ok(Outcome::FirstReadWrong)
+        } else {
+            Ok(Outcome::Flapping)
+        }
+    }
+
+    fn verify_entry(entry: &Entry, options: &HashOptions, report: &mut ScrubReport) -> Result<Outcome, ReadError> {
+        let path = Path::new(&entry.abspath);
+        if let Some(link_target) = &entry.link_target {
+            return match fs::read_link(path) {
+                Ok(target) if path_to_string(&target) == *link_target => Ok(Outcome::Verified),
+                Ok(_) => Ok(Outcome::Modified),
+                Err(any) if any.kind() == std::io::ErrorKind::NotFound => Ok(Outcome::Missing),
+                Err(any) => Err(ReadError::from(any)),
+            };
+        }
+
+        let metadata = match fs::metadata(path) {
+            Ok(any) => any,
+            Err(any) if any.kind() == std::io::ErrorKind::NotFound => return Ok(Outcome::Missing),
+            Err(any) => return Err(ReadError::from(any)),
+        };
+        if metadata.len() != entry.size
+            || metadata.mtime() != entry.timestamp
+            || entry.timestamp_nsec.is_some_and(|nsec| nsec != metadata.mtime_nsec()) {
+            return Ok(Outcome::Modified);
+        }
+
+        let hashed = hash_file(path, options)?;
+        if hashed.read_mode != options.read_mode {
+            report.fallback_reads += 1;
+        }
+        if hashed.signature == entry.signature {
+            Ok(Outcome::Verified)
+        } else {
+            Ok(Outcome::Corrupted(hashed))
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod scrub_tests {
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::{Path, PathBuf};
+    use rusqlite::Connection;
+    use crate::Database;
+    use crate::hashing::hashing::{FileSignature, HashAlgorithm, HashOptions, ReadMode};
+    use crate::model::model::Entry;
+    use crate::scrub::scrub::{double_check, scrub, Outcome, ScrubOptions, ScrubReport};
+    use crate::testing::testing::TempDir;
+
+    const HELLO: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    fn options() -> ScrubOptions {
+        ScrubOptions { update: false, duration: None, bytes: None, read_mode: ReadMode::Cached, double_check: false, retries: None }
+    }
+
+    /// Indexes the files under the directory with the given signatures, into a database next to them.
+    fn index_files(dir: &Path, files: &[(&str, &str)]) -> PathBuf {
+        let database_file = dir.join("index.db");
+        let connection = Connection::open(&database_file).unwrap();
+        let mut database = Database::new(&connection);
+        database.init_for(dir.to_str().unwrap(), None, 1000, false, None).unwrap();
+        for (name, signature) in files {
+            let path = dir.join(name);
+            let metadata = fs::metadata(&path).unwrap();
+            database.add_entry(&Entry::new(&path, dir, signature, metadata.len(), metadata.mtime(), 1000)).unwrap();
+        }
+        database_file
+    }
+
+    #[test]
+    fn unverifiable_entries_move_to_the_back() {
+        let dir = TempDir::new("scrub-missing");
+        fs::write(dir.join("gone"), b"hello").unwrap();
+        let database_file = index_files(&dir, &[("gone", HELLO)]);
+        fs::remove_file(dir.join("gone")).unwrap();
+        let first = scrub(&database_file, &options()).unwrap();
+        // indexed after the first scrub, so that it has never been verified.
+        fs::write(dir.join("kept"), b"hello").unwrap();
+        let connection = Connection::open(&database_file).unwrap();
+        let mut database = Database::new(&connection);
+        database.select_root(None, None).unwrap();
+        database.add_entry(&Entry::new(&dir.join("kept"), &dir, HELLO, 5, fs::metadata(dir.join("kept")).unwrap().mtime(), 1000)).unwrap();
+
+        // a budget of one file, which is spent on the entry which was never verified.
+        let second = scrub(&database_file, &ScrubOptions { bytes: Some(1), ..options() }).unwrap();
+        let statuses = database.count_by_verify_status().unwrap();
+
+        assert_eq!((0, 1), (first.verified, first.missing));
+        assert_eq!((1, 0, 1), (second.verified, second.missing, second.remaining));
+        assert_eq!(vec![("missing".to_string(), 1)], statuses);
+    }
+
+    #[test]
+    fn updated_entries_are_no_longer_mismatched() {
+        let dir = TempDir::new("scrub-update");
+        fs::write(dir.join("a"), b"hello").unwrap();
+        let database_file = index_files(&dir, &[("a", "00deadbeef")]);
+        let first = scrub(&database_file, &ScrubOptions { update: true, ..options() }).unwrap();
+        let connection = Connection::open(&database_file).unwrap();
+        let statuses = Database::new(&connection).count_by_verify_status().unwrap();
+        let second = scrub(&database_file, &options()).unwrap();
+
+        assert_eq!(1, first.corrupted.len());
+        assert!(statuses.is_empty());
+        assert_eq!((1, 0), (second.verified, second.corrupted.len()));
+    }
+
+    #[test]
+    fn double_check_reads_again() {
+        let dir = TempDir::new("scrub-double-check");
+        fs::write(dir.join("a"), b"hello").unwrap();
+        // stored as if the contents changed on storage since indexing.
+        let database_file = index_files(&dir, &[("a", "00deadbeef")]);
+        let report = scrub(&database_file, &ScrubOptions { double_check: true, ..options() }).unwrap();
+        let connection = Connection::open(&database_file).unwrap();
+        let statuses = Database::new(&connection).count_by_verify_status().unwrap();
+
+        let entry = Entry::new(&dir.join("a"), &dir, HELLO, 5, 0, 1000);
+        let hash_options = HashOptions { algorithm: HashAlgorithm::Sha256, read_mode: ReadMode::Cached, block_size: None, retries: None };
+        let first = || FileSignature { signature: "00c0ffee".to_string(), blocks: Vec::new(), read_mode: ReadMode::Cached };
+        let first_read_wrong = double_check(&entry, &hash_options, first(), &mut ScrubReport::default());
+        let flapping = double_check(&Entry { signature: "00deadbeef".to_string(), ..entry }, &hash_options, first(), &mut ScrubReport::default());
+
+        assert_eq!(1, report.corrupted.len());
+        assert_eq!(vec![("stable-mismatch".to_string(), 1)], statuses);
+        assert!(matches!(first_read_wrong, Ok(Outcome::FirstReadWrong)));
+        assert!(matches!(flapping, Ok(Outcome::Flapping)));
+    }
+}
@@ -0,0 +1,382 @@
+// Copyright (c) 2022-2025 Chris Kroells
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+pub mod traversal {
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::fs;
+    use std::fs::DirEntry;
+    use std::io::Error;
+    use std::ops::Add;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime};
+    use clap::ValueEnum;
+    use ignore::gitignore::Gitignore;
+    use log::{debug, error, info, warn};
+
+    use crate::filter::filter::{load_ignore_file, PathFilter};
+    use crate::indexing::indexing::IndexingError;
+
+    /// How symbolic links found during traversal are handled.
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+    pub enum SymlinkPolicy {
+        /// Index the link itself, using its target as the contents.
+        Record,
+        /// Index the file or directory the link points to.
+        Follow,
+        /// Ignore symbolic links.
+        #[default]
+        Skip,
+    }
+
+    /// Settings shared by all traversals of the root directory during a run.
+    pub struct TraversalOptions<'a> {
+        pub filter: &'a PathFilter,
+        pub symlinks: SymlinkPolicy,
+        /// Skip directories on a different file system than the root directory.
+        pub one_file_system: bool,
+        /// Mount points skipped so far, so that each one is only logged once per run.
+        pub skipped_mount_points: RefCell<HashSet<PathBuf>>,
+        /// Directories and files which could not be read, to be recorded by the caller.
+        pub errors: RefCell<Vec<(PathBuf, Error)>>,
+    }
+
+    impl TraversalOptions<'_> {
+        pub fn new(filter: &PathFilter, symlinks: SymlinkPolicy, one_file_system: bool) -> TraversalOptions<'_> {
+            TraversalOptions {
+                filter,
+                symlinks,
+                one_file_system,
+                skipped_mount_points: RefCell::new(HashSet::new()),
+                errors: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    pub(crate) struct Traversal<'a> {
+        callback: &'a dyn Fn(&DirEntry),
+        options: &'a TraversalOptions<'a>,
+        terminate_at: Option<SystemTime>,
+        /// Path of the last file processed by a previous run, which everything up to is skipped.
+        resume_after: Option<&'a Path>,
+        root_device: u64,
+        ignore_files: Vec<Gitignore>,
+        /// Device and inode numbers of the directories from the root to the current directory.
+        pub(crate) ancestors: Vec<(u64, u64)>,
+    }
+
+    /// Walks the directory tree, calling the callback for each file not excluded by the filter.
+    ///
+    /// Depending on the symlink policy, the callback is also called for symbolic links, either
+    /// for the link itself or for the file it points to.
+    ///
+    /// Entries of each directory are visited in sorted order, so that a traversal stopped with
+    /// `IndexingError::ExecutionTimeout` once the duration (in seconds) has passed can be resumed
+    /// after the last visited path.
+    pub fn traverse(root: &Path, callback: &dyn Fn(&DirEntry), options: &TraversalOptions, duration: Option<u64>, resume_after: Option<&Path>) -> Result<(), IndexingError> {
+        let (root_device, root_inode) = match fs::metadata(root) {
+            Ok(metadata) => (metadata.dev(), metadata.ino()),
+            Err(err) => {
+                error!("Error while attempting to read metadata of {:?}! -> {}", root, err);
+                return Err(IndexingError::ExecutionError(err, format!("Error while attempting to read metadata of {:?}!", root)));
+            }
+        };
+
+        Traversal::new(callback, options, duration, resume_after, root_device).traverse_dir(root, Some((root_device, root_inode)))
+    }
+
+    impl<'a> Traversal<'a> {
+        pub(crate) fn new(callback: &'a dyn Fn(&DirEntry), options: &'a TraversalOptions<'a>, duration: Option<u64>, resume_after: Option<&'a Path>, root_device: u64) -> Traversal<'a> {
+            Traversal {
+                callback,
+                options,
+                terminate_at: duration.map(|duration| SystemTime::now().add(Duration::from_secs(duration))),
+                resume_after,
+                root_device,
+                ignore_files: Vec::new(),
+                ancestors: Vec::new(),
+            }
+        }
+
+        /// Visits the entries of the directory, which is an ancestor of them while they are visited
+        /// when its device and inode numbers are known.
+        pub(crate) fn traverse_dir(&mut self, dir: &Path, ancestor: Option<(u64, u64)>) -> Result<(), IndexingError> {
+            let entries = match fs::read_dir(dir) {
+                Ok(any) => any,
+                Err(err) => {
+                    error!("Error while attempting to read entries in {:?}! -> {}", dir, err);
+                    let copy = Error::new(err.kind(), err.to_string());
+                    self.options.errors.borrow_mut().push((dir.to_path_buf(), err));
+                    return Err(
+                        IndexingError::ExecutionError(
+                            copy, format!("Error while attempting to read entries in {:?}!", dir)
+                        )
+                    );
+                }
+            };
+
+            let ignore_file = load_ignore_file(dir);
+            let has_ignore_file = ignore_file.is_some();
+            self.ignore_files.extend(ignore_file);
+            let has_ancestor = ancestor.is_some();
+            self.ancestors.extend(ancestor);
+
+            let mut sorted_entries = Vec::new();
+            for entry in entries {
+                match entry {
+                    Ok(any) => sorted_entries.push(any),
+                    Err(err) => error!("Error! -> {}", err),
+                }
+            }
+            sorted_entries.sort_by_key(|entry| entry.file_name());
+
+            let mut result = Ok(());
+            for entry in sorted_entries {
+                if self.terminate_at.is_some() && SystemTime::now() > self.terminate_at.unwrap() {
+                    info!("Execution timed out.");
+                    result = Err(IndexingError::ExecutionTimeout);
+                    break;
+                }
+
+                if self.is_before_resume_point(&entry.path()) {
+                    continue;
+                }
+
+                if let Err(IndexingError::ExecutionTimeout) = self.visit(&entry) {
+                    result = Err(IndexingError::ExecutionTimeout);
+                    break;
+                }
+            }
+
+            if has_ancestor {
+                self.ancestors.pop();
+            }
+            if has_ignore_file {
+                self.ignore_files.pop();
+            }
+            result
+        }
+
+        fn visit(&mut self, entry: &DirEntry) -> Result<(), IndexingError> {
+            let path = entry.path();
+            let file_type = match entry.file_type() {
+                Ok(any) => any,
+                Err(err) => {
+                    error!("Error while attempting to get file type of {:?}! -> {}", path, err);
+                    self.options.errors.borrow_mut().push((path, err));
+                    return Ok(());
+                }
+            };
+
+            let (is_dir, is_file) = match (file_type.is_symlink(), self.options.symlinks) {
+                (false, _) => (file_type.is_dir(), file_type.is_file()),
+                (true, SymlinkPolicy::Skip) => {
+                    debug!("skipping symlink -> {:?}", path);
+                    return Ok(());
+                },
+                (true, SymlinkPolicy::Record) => (false, true),
+                (true, SymlinkPolicy::Follow) => match fs::metadata(&path) {
+                    Ok(metadata) => {
+                        if metadata.is_dir() && self.ancestors.contains(&(metadata.dev(), metadata.ino())) {
+                            warn!("Skipping symlink which loops back to its parent directory -> {:?}", path);
+                            return Ok(());
+                        }
+                        (metadata.is_dir(), metadata.is_file())
+                    },
+                    Err(err) => {
+                        warn!("Skipping broken symlink {:?} -> {}", path, err);
+                        return Ok(());
+                    }
+                },
+            };
+
+            if is_dir {
+                if self.options.filter.is_excluded(&path, true, &self.ignore_files) {
+                    debug!("excluded directory -> {:?}", path);
+                    return Ok(());
+                }
+                if self.is_other_file_system(&path) {
+                    return Ok(());
+                }
+                let ancestor = fs::metadata(&path).ok().map(|metadata| (metadata.dev(), metadata.ino()));
+                // errors reading a subdirectory are logged, and the remaining entries processed.
+                if let Err(IndexingError::ExecutionTimeout) = self.traverse_dir(&path, ancestor) {
+                    return Err(IndexingError::ExecutionTimeout);
+                }
+            } else if is_file {
+                if self.options.filter.is_excluded(&path, false, &self.ignore_files) {
+                    debug!("excluded file -> {:?}", path);
+                    return Ok(());
+                }
+                (self.callback)(entry)
+            } else {
+                // skip any other types?
+            }
+            Ok(())
+        }
+
+        /// Checks whether the path was already processed by the run being resumed.
+        ///
+        /// As entries are visited in sorted order, these are the paths ordered before the resume
+        /// point, except for the directories containing it.
+        pub(crate) fn is_before_resume_point(&self, path: &Path) -> bool {
+            match self.resume_after {
+                Some(resume_after) => path == resume_after || (path < resume_after && !resume_after.starts_with(path)),
+                None => false,
+            }
+        }
+
+        pub(crate) fn is_other_file_system(&self, dir: &Path) -> bool {
+            if !self.options.one_file_system {
+                return false;
+            }
+            let device = match fs::metadata(dir) {
+                Ok(metadata) => metadata.dev(),
+                Err(_) => return false, // reading the directory will report the error.
+            };
+            if device == self.root_device {
+                return false;
+            }
+
+            if self.options.skipped_mount_points.borrow_mut().insert(dir.to_path_buf()) {
+                info!("Skipping mount point on a different file system -> {:?}", dir);
+            }
+            true
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod traversal_tests {
+    use std::cell::RefCell;
+    use std::fs;
+    use std::fs::DirEntry;
+    use std::os::unix::fs::{symlink, MetadataExt};
+    use std::path::Path;
+    use crate::filter::filter::PathFilter;
+    use crate::traversal::traversal::{traverse, SymlinkPolicy, Traversal, TraversalOptions};
+    use crate::testing::testing::TempDir;
+
+    /// Traverses the root, giving the paths passed to the callback relative to the root.
+    fn visited(root: &Path, symlinks: SymlinkPolicy, resume_after: Option<&Path>) -> Vec<String> {
+        let filter = PathFilter::new(root, &[], &[]).unwrap();
+        let options = TraversalOptions::new(&filter, symlinks, false);
+        let paths = RefCell::new(Vec::new());
+        let callback: &dyn Fn(&DirEntry) = &|entry| {
+            paths.borrow_mut().push(entry.path().strip_prefix(root).unwrap().to_str().unwrap().to_string());
+        };
+        traverse(root, callback, &options, None, resume_after).unwrap();
+        paths.into_inner()
+    }
+
+    fn traversal<'a>(options: &'a TraversalOptions<'a>, resume_after: Option<&'a Path>, root_device: u64) -> Traversal<'a> {
+        Traversal::new(&|_| {}, options, None, resume_after, root_device)
+    }
+
+    #[test]
+    fn before_resume_point() {
+        let filter = PathFilter::new(Path::new("/r"), &[], &[]).unwrap();
+        let options = TraversalOptions::new(&filter, SymlinkPolicy::Skip, false);
+        let traversal = traversal(&options, Some(Path::new("/r/b/c")), 0);
+
+        for path in ["/r/a", "/r/b/a", "/r/b/c"] {
+            assert!(traversal.is_before_resume_point(Path::new(path)), "{}", path);
+        }
+        // directories containing the resume point still have files after it.
+        for path in ["/r/b", "/r/b/d", "/r/b.txt", "/r/c"] {
+            assert!(!traversal.is_before_resume_point(Path::new(path)), "{}", path);
+        }
+    }
+
+    #[test]
+    fn resumes_in_sorted_order() {
+        let root = TempDir::new("traversal-resume");
+        fs::create_dir_all(root.join("b")).unwrap();
+        for path in ["c", "b/y", "a", "b/x"] {
+            fs::write(root.join(path), b"hello").unwrap();
+        }
+        let all = visited(&root, SymlinkPolicy::Skip, None);
+        let resumed = visited(&root, SymlinkPolicy::Skip, Some(&root.join("b/x")));
+
+        assert_eq!(vec!["a", "b/x", "b/y", "c"], all);
+        assert_eq!(vec!["b/y", "c"], resumed);
+    }
+
+    #[test]
+    fn symlink_policies() {
+        let root = TempDir::new("traversal-symlinks");
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::write(root.join("file"), b"hello").unwrap();
+        fs::write(root.join("dir/inner"), b"hello").unwrap();
+        symlink(root.join("file"), root.join("link")).unwrap();
+        symlink(root.join("dir"), root.join("linked_dir")).unwrap();
+        symlink(root.join("missing"), root.join("broken")).unwrap();
+        // would be followed forever without the loop detection.
+        symlink(&root, root.join("dir/loop")).unwrap();
+        let [skipped, recorded, followed] = [SymlinkPolicy::Skip, SymlinkPolicy::Record, SymlinkPolicy::Follow]
+            .map(|symlinks| visited(&root, symlinks, None));
+
+        assert_eq!(vec!["dir/inner", "file"], skipped);
+        assert_eq!(vec!["broken", "dir/inner", "dir/loop", "file", "link", "linked_dir"], recorded);
+        assert_eq!(vec!["dir/inner", "file", "link", "linked_dir/inner"], followed);
+    }
+
+    #[test]
+    fn ancestors_stay_balanced() {
+        let root = TempDir::new("traversal-ancestors");
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::write(root.join("dir/file"), b"hello").unwrap();
+        let metadata = fs::metadata(&root).unwrap();
+        let filter = PathFilter::new(&root, &[], &[]).unwrap();
+        let options = TraversalOptions::new(&filter, SymlinkPolicy::Follow, false);
+        let mut traversal = traversal(&options, None, metadata.dev());
+        // as if entered from a parent directory.
+        traversal.ancestors.push((0, 0));
+
+        traversal.traverse_dir(&root, Some((metadata.dev(), metadata.ino()))).unwrap();
+        // as if the metadata of the directory could not be read.
+        traversal.traverse_dir(&root, None).unwrap();
+        // left right away, as its entries cannot be read.
+        assert!(traversal.traverse_dir(&root.join("missing"), None).is_err());
+        assert_eq!(vec![(0, 0)], traversal.ancestors);
+    }
+
+    #[test]
+    fn other_file_system() {
+        let temp_dir = TempDir::new("traversal-mounts");
+        let dir = temp_dir.to_path_buf();
+        let device = fs::metadata(&dir).unwrap().dev();
+        let filter = PathFilter::new(&dir, &[], &[]).unwrap();
+        let options = TraversalOptions::new(&filter, SymlinkPolicy::Skip, true);
+
+        assert!(!traversal(&options, None, device).is_other_file_system(&dir));
+        // as if the root were on another device than the directory.
+        let other_device = traversal(&options, None, device + 1);
+        assert!(other_device.is_other_file_system(&dir));
+        assert!(other_device.is_other_file_system(&dir));
+        assert!(!other_device.is_other_file_system(&dir.join("mitsubachi-missing")));
+        assert_eq!(vec![dir.clone()], Vec::from_iter(options.skipped_mount_points.take()));
+
+        let options = TraversalOptions::new(&filter, SymlinkPolicy::Skip, false);
+        assert!(!traversal(&options, None, device + 1).is_other_file_system(&dir));
+    }
+}
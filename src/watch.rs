@@ -0,0 +1,477 @@
+// Copyright (c) 2022-2025 Chris Kroells
+// 
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// 
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+// 
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+pub mod watch {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::ffi::{CString, OsStr};
+    use std::fs;
+    use std::fs::DirEntry;
+    use std::io::{Error, ErrorKind};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant};
+    use ignore::gitignore::Gitignore;
+    use log::{debug, error, info, warn};
+
+    use crate::filter::filter::{load_ignore_file, PathFilter};
+    use crate::indexing::indexing::{index, index_paths, new_filter, IndexingOptions};
+    use crate::traversal::traversal::{traverse, SymlinkPolicy, TraversalOptions};
+
+    /// Events which make a file or directory under a watched directory worth looking at again.
+    const WATCH_MASK: u32 = libc::IN_MODIFY | libc::IN_CLOSE_WRITE | libc::IN_ATTRIB | libc::IN_CREATE
+        | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_DELETE_SELF
+        | libc::IN_MOVE_SELF | libc::IN_ONLYDIR;
+
+    pub struct WatchOptions {
+        /// Number of seconds a path has to go without events before it is indexed.
+        pub debounce: u64,
+        /// Skip the full pass bringing the index up to date before watching for changes.
+        pub skip_initial_index: bool,
+    }
+
+    /// Directories of the root watched by an inotify instance.
+    pub(crate) struct Watcher<'a> {
+        fd: OwnedFd,
+        root: &'a Path,
+        root_device: u64,
+        filter: &'a PathFilter,
+        options: &'a IndexingOptions,
+        /// Path of the database file, so that writing to it does not trigger indexing when it is under the root.
+        database_path: PathBuf,
+        /// Watched directories by watch descriptor.
+        pub(crate) dirs: HashMap<i32, PathBuf>,
+        /// Paths with events which have not been indexed yet, with the time of their last event.
+        pub(crate) pending: HashMap<PathBuf, Instant>,
+        pub(crate) overflowed: bool,
+    }
+
+    /// Keeps the index of the root up to date, by watching its directories for changes with inotify
+    /// and indexing only the paths which changed. Runs until the root is removed or an error occurs.
+    ///
+    /// Changed paths are indexed once they have gone without events for the debounce period, so
+    /// that files being written are not hashed over and over. When the kernel drops events as the
+    /// queue overflowed, a full pass is run instead, as for the `index` command.
+    ///
+    /// Symbolic links to directories are not watched, so changes under them are only picked up
+    /// by full passes.
+    pub fn watch(output_file: &Path, root: &Path, options: &WatchOptions, indexing_options: &IndexingOptions) -> Result<(), Error> {
+        let root_device = match fs::metadata(root) {
+            Ok(metadata) if metadata.is_dir() => metadata.dev(),
+            Ok(_) => {
+                error!("Specified root directory is not a directory: {:?}", root);
+                return Err(Error::new(ErrorKind::InvalidInput, format!("Not a directory: {:?}", root)));
+            },
+            Err(err) => {
+                error!("Error while attempting to read metadata of {:?}! -> {}", root, err);
+                return Err(err);
+            }
+        };
+        let filter = new_filter(root, indexing_options)?;
+
+        let mut watcher = Watcher::new(root, root_device, &filter, indexing_options, output_file)?;
+        // watches are added first, so that nothing changed during the initial pass is missed.
+        watcher.add_watches(root);
+        info!("Watching {} directories under {:?}", watcher.dirs.len(), root);
+        if !options.skip_initial_index {
+            index(output_file, root, indexing_options).map_err(Error::other)?;
+        }
+
+        let debounce = Duration::from_secs(options.debounce);
+        loop {
+            let timeout = match watcher.pending.is_empty() {
+                true => -1,
+                false => debounce.as_millis().max(1) as i32,
+            };
+            if watcher.wait(timeout)? {
+                watcher.read_events()?;
+            }
+
+            match watcher.next_update(debounce) {
+                Some(Update::Full) => {
+                    index(output_file, root, indexing_options).map_err(Error::other)?;
+                },
+                Some(Update::Paths(paths)) => {
+                    info!("Indexing {} changed paths.", paths.len());
+                    index_paths(output_file, root, &paths, indexing_options).map_err(Error::other)?;
+                },
+                None => {},
+            }
+        }
+    }
+
+    /// Indexing to do after reading the events.
+    #[derive(Debug, PartialEq, Eq)]
+    pub(crate) enum Update {
+        /// Events were dropped, so that only a full pass finds all changes.
+        Full,
+        /// Index these files, whose paths have gone without events for the debounce period.
+        Paths(Vec<PathBuf>),
+    }
+
+    /// Gives the absolute path of a file which may not exist yet, resolving its directory.
+    fn absolute_path(path: &Path) -> PathBuf {
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        match (fs::canonicalize(dir), path.file_name()) {
+            (Ok(dir), Some(name)) => dir.join(name),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    impl<'a> Watcher<'a> {
+        pub(crate) fn new(root: &'a Path, root_device: u64, filter: &'a PathFilter, options: &'a IndexingOptions, output_file: &Path) -> Result<Watcher<'a>, Error> {
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+            if fd < 0 {
+                let err = Error::last_os_error();
+                error!("Error while attempting to initialize inotify! -> {}", err);
+                return Err(err);
+            }
+            Ok(Watcher {
+                fd: unsafe { OwnedFd::from_raw_fd(fd) },
+                root,
+                root_device,
+                filter,
+                options,
+                database_path: absolute_path(output_file),
+                dirs: HashMap::new(),
+                pending: HashMap::new(),
+                overflowed: false,
+            })
+        }
+
+        /// Takes the queued paths which have gone without events for the debounce period, or asks
+        /// for a full pass after the queue overflowed.
+        pub(crate) fn next_update(&mut self, debounce: Duration) -> Option<Update> {
+            if self.overflowed {
+                warn!("Events were dropped as the inotify queue overflowed, falling back to a full pass.");
+                self.overflowed = false;
+                self.pending.clear();
+                // directories created while events were dropped are not watched yet.
+                self.add_watches(self.root);
+                return Some(Update::Full);
+            }
+
+            let ready: Vec<PathBuf> = self.pending.iter()
+                .filter(|(_, last_event)| last_event.elapsed() >= debounce)
+                .map(|(path, _)| path.clone())
+                .collect();
+            if ready.is_empty() {
+                return None;
+            }
+            for path in &ready {
+                self.pending.remove(path);
+            }
+            let paths = self.expand(ready);
+            match paths.is_empty() {
+                true => None,
+                false => Some(Update::Paths(paths)),
+            }
+        }
+
+        /// Waits up to the timeout in milliseconds, or forever when negative, for events to read.
+        pub(crate) fn wait(&self, timeout: i32) -> Result<bool, Error> {
+            let mut poll_fd = libc::pollfd { fd: self.fd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+            let result = unsafe { libc::poll(&mut poll_fd, 1, timeout) };
+            if result < 0 {
+                let err = Error::last_os_error();
+                return match err.kind() {
+                    ErrorKind::Interrupted => Ok(false),
+                    _ => {
+                        error!("Error while waiting for inotify events! -> {}", err);
+                        Err(err)
+                    }
+                };
+            }
+            Ok(result > 0)
+        }
+
+        /// Reads the available events, queueing the paths they are about.
+        pub(crate) fn read_events(&mut self) -> Result<(), Error> {
+            // aligned for the event structs.
+            let mut buffer = vec![0u64; 8192];
+            let length = unsafe { libc::read(self.fd.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len() * 8) };
+            if length < 0 {
+                let err = Error::last_os_error();
+                return match err.kind() {
+                    ErrorKind::Interrupted | ErrorKind::WouldBlock => Ok(()),
+                    _ => {
+                        error!("Error while reading inotify events! -> {}", err);
+                        Err(err)
+                    }
+                };
+            }
+
+            let bytes = unsafe { std::slice::from_raw_parts(buffer.as_ptr().cast::<u8>(), length as usize) };
+            let header_size = std::mem::size_of::<libc::inotify_event>();
+            let mut offset = 0;
+            while offset + header_size <= bytes.len() {
+                let event = unsafe { std::ptr::read_unaligned(bytes[offset ..].as_ptr().cast::<libc::inotify_event>()) };
+                let name_bytes = &bytes[offset + header_size .. offset + header_size + event.len as usize];
+                // the name is padded with null bytes.
+                let name_length = name_bytes.iter().position(|byte| *byte == 0).unwrap_or(name_bytes.len());
+                self.handle_event(&event, OsStr::from_bytes(&name_bytes[.. name_length]))?;
+                offset += header_size + event.len as usize;
+            }
+            Ok(())
+        }
+
+        pub(crate) fn handle_event(&mut self, event: &libc::inotify_event, name: &OsStr) -> Result<(), Error> {
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                self.overflowed = true;
+                return Ok(());
+            }
+            if event.mask & libc::IN_IGNORED != 0 {
+                self.dirs.remove(&event.wd);
+                return Ok(());
+            }
+            let dir = match self.dirs.get(&event.wd) {
+                Some(dir) => dir.clone(),
+                None => return Ok(()), // a directory which is no longer watched.
+            };
+            if name.is_empty() {
+                // events about the directory itself are also reported to its parent, except for the root.
+                if dir == self.root && event.mask & (libc::IN_DELETE_SELF | libc::IN_MOVE_SELF) != 0 {
+                    error!("Root directory was removed or moved: {:?}", self.root);
+                    return Err(Error::new(ErrorKind::NotFound, format!("Root directory was removed or moved: {:?}", self.root)));
+                }
+                return Ok(());
+            }
+
+            let path = dir.join(name);
+            debug!("event {:#x} -> {:?}", event.mask, path);
+            if event.mask & libc::IN_ISDIR != 0 && event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                // watched right away, so that files created in it before it is indexed are not missed.
+                self.add_watches(&path);
+            }
+            self.pending.insert(path, Instant::now());
+            Ok(())
+        }
+
+        /// Watches the directory and the directories under it, unless excluded.
+        pub(crate) fn add_watches(&mut self, dir: &Path) {
+            let mut ignore_files = match dir.parent() {
+                Some(parent) if dir != self.root => self.ignore_files(parent),
+                _ => Vec::new(),
+            };
+            self.add_watches_under(dir, &mut ignore_files);
+        }
+
+        fn add_watches_under(&mut self, dir: &Path, ignore_files: &mut Vec<Gitignore>) {
+            let path = CString::new(dir.as_os_str().as_bytes()).unwrap();
+            let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), WATCH_MASK) };
+            if wd < 0 {
+                let err = Error::last_os_error();
+                error!("Error while attempting to watch {:?}! -> {}", dir, err);
+                if err.raw_os_error() == Some(libc::ENOSPC) {
+                    warn!("The limit of inotify watches was reached, changes under {:?} are only picked up by full passes. \
+                        Raise fs.inotify.max_user_watches to watch all directories.", dir);
+                }
+                return;
+            }
+            // a directory moved within the root keeps its watch descriptor.
+            self.dirs.insert(wd, dir.to_path_buf());
+
+            let entries = match fs::read_dir(dir) {
+                Ok(any) => any,
+                Err(err) => {
+                    error!("Error while attempting to read entries in {:?}! -> {}", dir, err);
+                    return;
+                }
+            };
+            let ignore_file = load_ignore_file(dir);
+            let has_ignore_file = ignore_file.is_some();
+            ignore_files.extend(ignore_file);
+            for entry in entries.flatten() {
+                if !entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                    continue;
+                }
+                let path = entry.path();
+                if self.filter.is_excluded(&path, true, ignore_files) || self.is_other_file_system(&path) {
+                    debug!("not watching excluded directory -> {:?}", path);
+                    continue;
+                }
+                self.add_watches_under(&path, ignore_files);
+            }
+            if has_ignore_file {
+                ignore_files.pop();
+            }
+        }
+
+        /// Stops watching the directories at or under the path, such as when it moved out of the root.
+        fn remove_watches(&mut self, path: &Path) {
+            let removed: Vec<i32> = self.dirs.iter()
+                .filter(|(_, dir)| dir.starts_with(path))
+                .map(|(wd, _)| *wd)
+                .collect();
+            for wd in removed {
+                // the kernel confirms with an IN_IGNORED event, which forgets the directory.
+                unsafe { libc::inotify_rm_watch(self.fd.as_raw_fd(), wd) };
+            }
+        }
+
+        /// Turns the queued paths into the files to index, by listing the files of directories and
+        /// leaving out excluded files. Paths which no longer exist are kept, to remove their entries.
+        fn expand(&mut self, paths: Vec<PathBuf>) -> Vec<PathBuf> {
+            let mut files = Vec::new();
+            for path in paths {
+                if self.is_database_file(&path) {
+                    continue;
+                }
+                let metadata = match fs::symlink_metadata(&path) {
+                    Ok(metadata) => metadata,
+                    Err(_) => {
+                        self.remove_watches(&path);
+                        files.push(path);
+                        continue;
+                    }
+                };
+                let is_dir = match (metadata.is_symlink(), self.options.symlinks) {
+                    (false, _) => metadata.is_dir(),
+                    (true, SymlinkPolicy::Skip) => continue,
+                    (true, SymlinkPolicy::Record) => false,
+                    (true, SymlinkPolicy::Follow) => fs::metadata(&path).is_ok_and(|metadata| metadata.is_dir()),
+                };
+                if self.filter.is_excluded(&path, is_dir, &self.ignore_files(path.parent().unwrap())) {
+                    debug!("excluded -> {:?}", path);
+                    continue;
+                }
+                if !is_dir {
+                    files.push(path);
+                    continue;
+                }
+
+                let traversal_options = TraversalOptions::new(self.filter, self.options.symlinks, self.options.one_file_system);
+                let found = RefCell::new(Vec::new());
+                let callback: &dyn Fn(&DirEntry) = &|dir_entry| found.borrow_mut().push(dir_entry.path());
+                if let Err(any) = traverse(&path, callback, &traversal_options, None, None) {
+                    warn!("Error occurred while listing files under {:?}. caused by: {}", path, any);
+                }
+                files.extend(found.into_inner().into_iter().filter(|file| !self.is_database_file(file)));
+            }
+            // files in a new directory may also have events of their own.
+            files.sort();
+            files.dedup();
+            files
+        }
+
+        /// Loads the ignore files from the root down to the directory.
+        fn ignore_files(&self, dir: &Path) -> Vec<Gitignore> {
+            let mut dirs: Vec<&Path> = dir.ancestors().take_while(|ancestor| ancestor.starts_with(self.root)).collect();
+            dirs.reverse();
+            dirs.into_iter().filter_map(load_ignore_file).collect()
+        }
+
+        fn is_other_file_system(&self, dir: &Path) -> bool {
+            self.options.one_file_system && fs::metadata(dir).is_ok_and(|metadata| metadata.dev() != self.root_device)
+        }
+
+        /// Checks whether the path is the database file, or one of the files sqlite keeps next to it.
+        fn is_database_file(&self, path: &Path) -> bool {
+            let path = absolute_path(path);
+            path.as_os_str().as_bytes().strip_prefix(self.database_path.as_os_str().as_bytes())
+                .is_some_and(|suffix| suffix.is_empty() || suffix.starts_with(b"-"))
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod watch_tests {
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+    use std::time::Duration;
+    use crate::filter::filter::PathFilter;
+    use crate::indexing::indexing::IndexingOptions;
+    use crate::testing::testing::TempDir;
+    use crate::watch::watch::{Update, Watcher};
+
+    fn watcher<'a>(root: &'a Path, filter: &'a PathFilter, options: &'a IndexingOptions) -> Watcher<'a> {
+        let root_device = fs::metadata(root).unwrap().dev();
+        let mut watcher = Watcher::new(root, root_device, filter, options, &root.with_extension("db")).unwrap();
+        watcher.add_watches(root);
+        watcher
+    }
+
+    /// Reads the events until none arrive for a moment.
+    fn read_all(watcher: &mut Watcher) {
+        while watcher.wait(100).unwrap() {
+            watcher.read_events().unwrap();
+        }
+    }
+
+    #[test]
+    fn coalesces_events() {
+        let temp_dir = TempDir::new("watch-coalesce");
+        let root = temp_dir.join("root");
+        fs::create_dir(&root).unwrap();
+        let filter = PathFilter::new(&root, &[], &[]).unwrap();
+        let options = IndexingOptions { no_sync: true, ..Default::default() };
+        let mut watcher = watcher(&root, &filter, &options);
+
+        fs::write(root.join("a"), b"hello").unwrap();
+        fs::write(root.join("a"), b"hello, world").unwrap();
+        fs::create_dir(root.join("dir")).unwrap();
+        read_all(&mut watcher);
+        // created after the directory was watched, so that it has events of its own.
+        fs::write(root.join("dir/b"), b"hello").unwrap();
+        read_all(&mut watcher);
+        let pending = watcher.pending.len();
+        let waiting = watcher.next_update(Duration::from_secs(3600));
+        let update = watcher.next_update(Duration::ZERO);
+        let is_dir_watched = watcher.dirs.values().any(|dir| *dir == root.join("dir"));
+
+        assert_eq!(3, pending);
+        assert_eq!(None, waiting);
+        assert_eq!(Some(Update::Paths(vec![root.join("a"), root.join("dir/b")])), update);
+        assert!(is_dir_watched);
+        assert!(watcher.pending.is_empty());
+    }
+
+    #[test]
+    fn full_pass_after_overflow() {
+        let temp_dir = TempDir::new("watch-overflow");
+        let root = temp_dir.join("root");
+        fs::create_dir(&root).unwrap();
+        let filter = PathFilter::new(&root, &[], &[]).unwrap();
+        let options = IndexingOptions { no_sync: true, ..Default::default() };
+        let mut watcher = watcher(&root, &filter, &options);
+
+        fs::write(root.join("a"), b"hello").unwrap();
+        read_all(&mut watcher);
+        let overflow = libc::inotify_event { wd: -1, mask: libc::IN_Q_OVERFLOW, cookie: 0, len: 0 };
+        watcher.handle_event(&overflow, OsStr::new("")).unwrap();
+        // its event is taken as dropped, as it is not read before the full pass.
+        fs::create_dir(root.join("dir")).unwrap();
+        let update = watcher.next_update(Duration::ZERO);
+        let is_dir_watched = watcher.dirs.values().any(|dir| *dir == root.join("dir"));
+
+        assert_eq!(Some(Update::Full), update);
+        assert!(watcher.pending.is_empty());
+        assert!(!watcher.overflowed);
+        assert!(is_dir_watched);
+    }
+}